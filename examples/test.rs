@@ -19,6 +19,8 @@ fn main() {
     */
 }
 
+// The bias is broadcasted, its gradient is summed back with `sum(axis, true)` over the axes where `shape()` is
+// smaller, so the tensor type needs both
 #[derive(Clone)]
 struct Test {
     input: Option<Tensor>,
@@ -28,7 +30,8 @@ struct Test {
 
 impl Layer for Test {
 
-    #[into_backward(weight, bias)]
+    // `*` of two tensors is a matrix product for this tensor type, the default is elementwise
+    #[into_backward(weight, bias, mul = "matmul")]
     fn forward(&self, input: &Tensor) -> Tensor {
         //let a = -input.clamp_min(0.);
         &self.weight*input + &self.bias
@@ -0,0 +1,84 @@
+use syn::parse::{Parse, ParseStream};
+use syn::punctuated::Punctuated;
+use syn::*;
+use proc_macro2::TokenStream;
+use quote::quote;
+
+// An argument of the attribute, a field or option, `name = "value"` or a list of derivative rules
+pub enum AttributeArg {
+    Path(Ident),
+    NameValue(Ident, Lit),
+    Diff(Punctuated<DiffRule, Token![,]>),
+    ForwardDiff(Punctuated<DiffRule, Token![,]>),
+}
+
+// A rule like `my_act = grad * {a}.my_act_deriv()`, with a tuple of expressions for methods with more operands
+pub struct DiffRule {
+    pub method: Ident,
+    pub expressions: Vec<TokenStream>,
+}
+
+impl Parse for AttributeArg {
+    fn parse(input: ParseStream) -> Result<Self> {
+        let ident: Ident = input.parse()?;
+        if (ident == "diff" || ident == "forward_diff") && input.peek(token::Paren) {
+            let content;
+            parenthesized!(content in input);
+            let rules = content.parse_terminated(DiffRule::parse)?;
+            return Ok(if ident == "diff" { AttributeArg::Diff(rules) } else { AttributeArg::ForwardDiff(rules) });
+        }
+        if input.peek(Token![=]) {
+            input.parse::<Token![=]>()?;
+            return Ok(AttributeArg::NameValue(ident, input.parse()?));
+        }
+        Ok(AttributeArg::Path(ident))
+    }
+}
+
+impl Parse for DiffRule {
+    fn parse(input: ParseStream) -> Result<Self> {
+        let method: Ident = input.parse()?;
+        input.parse::<Token![=]>()?;
+        let expressions = match input.parse::<Expr>()? {
+            Expr::Tuple(tuple) => tuple.elems.iter().map(|expr| quote!(#expr)).collect(),
+            expr => vec![quote!(#expr)]
+        };
+        Ok(DiffRule { method, expressions })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use syn::parse::Parser;
+
+    fn parse_args(tokens: TokenStream) -> Vec<AttributeArg> {
+        Punctuated::<AttributeArg, Token![,]>::parse_terminated.parse2(tokens).unwrap().into_iter().collect()
+    }
+
+    #[test]
+    fn parse_attribute_args() {
+        let args = parse_args(quote!(weight, mul = "matmul", diff(my_act = grad * {out}), jvp,
+            forward_diff(my_act = tangent * {out}, my_op = (tangent, tangent))));
+        assert_eq!(args.len(), 5);
+        assert!(matches!(&args[0], AttributeArg::Path(ident) if ident == "weight"));
+        assert!(matches!(&args[1], AttributeArg::NameValue(ident, Lit::Str(method))
+            if ident == "mul" && method.value() == "matmul"));
+        assert!(matches!(&args[2], AttributeArg::Diff(rules) if rules.len() == 1));
+        assert!(matches!(&args[3], AttributeArg::Path(ident) if ident == "jvp"));
+        assert!(matches!(&args[4], AttributeArg::ForwardDiff(rules) if rules.len() == 2));
+        assert!(Punctuated::<AttributeArg, Token![,]>::parse_terminated.parse2(quote!(mul = matmul)).is_err());
+    }
+
+    #[test]
+    fn parse_diff_rule() {
+        let rule: DiffRule = parse2(quote!(my_act = grad * {out} * (1. - {out}))).unwrap();
+        assert_eq!(rule.method, "my_act");
+        assert_eq!(rule.expressions.len(), 1);
+        // A tuple gives a rule for every operand
+        let rule: DiffRule = parse2(quote!(my_op = (grad * {b}, {a} * grad))).unwrap();
+        assert_eq!(rule.expressions.iter().map(|expr| expr.to_string()).collect::<Vec<_>>(),
+            vec![quote!(grad * {b}).to_string(), quote!({a} * grad).to_string()]);
+        assert!(parse2::<DiffRule>(quote!(my_act grad)).is_err());
+    }
+}
@@ -1,66 +1,154 @@
-use proc_macro2::TokenStream;
+use proc_macro2::{Literal, TokenStream};
 use std::collections::HashMap;
 use quote::quote;
+use syn::fold::{self, Fold};
+use syn::{Expr, Stmt};
+use crate::reader::Operation;
+
+// Defined before the tables that use it, macros are only visible after their definition
+macro_rules! add_diff {
+    ($owner:path, $func:literal $(, $diff:expr)* ) => {
+        {
+            #[allow(unused_mut)]
+            let mut expressions: Vec<proc_macro2::TokenStream> = Vec::new();
+            $(
+                expressions.push( quote! {
+                    $diff
+                });
+            )*
+            $owner.add_diff($func.to_string(), expressions);
+        }
+    };
+}
 
-static ARRGUMENT_NAMES: &[&str] = &["{ a }", "{ b }", "{ c }", "{ d }", "{ e }", "{ f }", "{ g }"];
 pub static OUTPUT_NAMES: &[&str] = &["a", "b", "c", "d", "e", "f", "g"];
+// `{out}` is the output of the operation in the forward pass, stored after the operands in the used variables
+pub static RESULT_NAME: &str = "out";
+pub const RESULT: u8 = u8::MAX;
+// Shape operations whose rules read the shape of the receiver with `{a}.shape()`
+pub static SHAPE_METHODS: &[&str] = &["reshape", "view", "flatten", "squeeze", "broadcast_to", "sum_to"];
 
 pub struct AutoDiff {
-    map: HashMap<String, Vec<(TokenStream, Vec<u8>)>>
+    map: HashMap<String, Vec<(Expr, Vec<u8>)>>,
+    forward: bool,
+}
+
+// Replaces the `{a}`, `{b-1}`, `{out}`, ... placeholders of a rule by the plain operand names
+struct Placeholders {
+    contains_var: Vec<u8>,
+    in_placeholder: bool,
+}
+
+impl Fold for Placeholders {
+    fn fold_expr(&mut self, ii: Expr) -> Expr {
+        if let Expr::Block(i) = &ii {
+            if let [Stmt::Expr(inner)] = i.block.stmts.as_slice() {
+                self.in_placeholder = true;
+                let inner = self.fold_expr(inner.clone());
+                self.in_placeholder = false;
+                return match inner {
+                    Expr::Path(_) => inner,
+                    _ => syn::parse_quote!((#inner))
+                };
+            }
+        }
+        fold::fold_expr(self, ii)
+    }
+
+    fn fold_ident(&mut self, ii: syn::Ident) -> syn::Ident {
+        if self.in_placeholder {
+            let var = if ii == RESULT_NAME {
+                Some(RESULT)
+            } else {
+                OUTPUT_NAMES.iter().position(|name| ii == name).map(|i| i as u8)
+            };
+            if let Some(var) = var {
+                if !self.contains_var.contains(&var) {
+                    self.contains_var.push(var);
+                }
+            }
+        }
+        ii
+    }
 }
 
 impl AutoDiff {
 
     pub fn new() -> AutoDiff {
         let mut s = AutoDiff {
-            map: HashMap::new()
+            map: HashMap::new(),
+            forward: false,
         };
         s.init();
         s
     }
 
+    /// Table of forward-mode rules, every expression gives the tangent of the output for the tangent of one operand.
+    pub fn new_forward() -> AutoDiff {
+        let mut s = AutoDiff {
+            map: HashMap::new(),
+            forward: true,
+        };
+        s.init_forward();
+        s
+    }
+
     pub fn add_diff(&mut self, method: String, expressions: Vec<TokenStream>) {
-        let map_entries: Vec<(TokenStream, Vec<u8>)> = expressions.iter().map(|ts| {
-            let mut s = ts.to_string();
-            let mut contains_var: Vec<u8> = Vec::new();
-            for i in 0..ARRGUMENT_NAMES.len() {
-                if s.contains(ARRGUMENT_NAMES[i]) {
-                    s = s.replace(ARRGUMENT_NAMES[i], OUTPUT_NAMES[i]);
-                    contains_var.push(i as u8);
-                }
-            }
-            (s.parse().unwrap(), contains_var)
+        let map_entries: Vec<(Expr, Vec<u8>)> = expressions.into_iter().map(|ts| {
+            let mut placeholders = Placeholders { contains_var: Vec::new(), in_placeholder: false };
+            let expr = placeholders.fold_expr(syn::parse2(ts).expect("Invalid diff expression"));
+            placeholders.contains_var.sort();
+            (expr, placeholders.contains_var)
         }).collect();
             
         self.map.insert(method, map_entries);
     }
 
-    pub fn get_expressions(&self, method: &str) -> &Vec<(TokenStream, Vec<u8>)> {
-        self.map.get(method).expect(&format!("No diff found for function {}", method))
+    // A rule for a number of arguments, like `transpose/2`, comes before the rule for the method. Functions like
+    // `Tensor::cat` use the rule of their name
+    pub fn get_expressions(&self, operation: &Operation) -> Vec<(Expr, Vec<u8>)> {
+        let method = operation.method.rsplit("::").next().unwrap().trim();
+        let operands = operation.args.len() + 1;
+        // The gradient of a list of tensors, like the operands of cat, has a part for every tensor in it
+        if method == "list" && !self.forward {
+            return (0..operands).map(|i| {
+                let index = Literal::usize_unsuffixed(i);
+                (syn::parse_quote!(grad[#index].clone()), Vec::new())
+            }).collect();
+        }
+        let expressions = match self.map.get(&format!("{}/{}", method, operation.args.len())).or_else(|| self.map.get(method)) {
+            Some(expressions) => expressions,
+            // Rules given with diff(...) are only used in reverse mode
+            None if self.forward => panic!("No forward diff found for function {}, it can be given with forward_diff(...)", method),
+            None => panic!("No diff found for function {}", method)
+        };
+        // A rule written for more operands, like a reduction over an axis used without one, can not be expanded
+        for (_, vars) in expressions.iter().take(operands) {
+            if vars.iter().any(|var| *var != RESULT && *var as usize >= operands) {
+                panic!("The diff of {} needs more arguments than the {} it is called with", method, operation.args.len());
+            }
+        }
+        expressions.clone()
     }
 
-    pub fn init(&mut self) {
+    // An operand with `0.` as its rule, like the indices of a lookup, gets no gradient, so operands after it can
+    // still have one
+    pub fn is_zero(expr: &Expr) -> bool {
+        matches!(expr, Expr::Lit(lit) if quote!(#lit).to_string() == "0.")
+    }
 
-        // Can we... move this? Rust is weird in where you can define and then use macros
-        macro_rules! add_diff {
-            ($owner:path, $func:literal, $($diff:expr),* ) => {
-                {
-                    let mut expressions: Vec<proc_macro2::TokenStream> = Vec::new();
-                    $(
-                        expressions.push( quote! {
-                            $diff
-                        });
-                    )*
-                    $owner.add_diff($func.to_string(), expressions);
-                }
-            };
-        }
+    pub fn init(&mut self) {
 
         //We should resolve type and accept function paths instead, but for now this works
-        add_diff!(self, "add", grad.clone(), grad);
-        add_diff!(self, "sub", grad.clone(), -grad);
-        add_diff!(self, "mul", (&grad)*{b}.transpose(), {a}.transpose()*grad);
-        add_diff!(self, "div", (&grad)/{b}, -(grad*{a})/(&({b}).powi(2)));
+        // The solver borrows grad and the operands until their last use, so rules take them by value
+        add_diff!(self, "add", grad, grad);
+        add_diff!(self, "sub", grad, -grad);
+        // Operators are elementwise, matrix products are methods. A broadcasted field is summed when it is stored
+        add_diff!(self, "mul", grad*{b}, {a}*grad);
+        add_diff!(self, "matmul", grad.matmul(&{b}.transpose()), {a}.transpose().matmul(&grad));
+        add_diff!(self, "bmm", grad.bmm(&{b}.transpose(1, 2)), {a}.transpose(1, 2).bmm(&grad));
+        add_diff!(self, "dot", grad*{b}, {a}*grad);
+        add_diff!(self, "div", grad/{b}, -(grad*{a})/({b}).powi(2));
         add_diff!(self, "neg", -grad);
         add_diff!(self, "sin", grad*{a}.cos());
         add_diff!(self, "cos", grad*(-{a}.sin()));
@@ -70,11 +158,190 @@ impl AutoDiff {
         add_diff!(self, "tanh", grad/({a}.cosh()).powi(2));
         add_diff!(self, "exp", grad*{a}.exp());
         add_diff!(self, "ln", grad/{a});
-        add_diff!(self, "clamp", grad*({a}.is_between({b}, {c}))); //Not complete
-        add_diff!(self, "clamp_min", grad*{a}.is_bigger({b})); //Not complete
-        add_diff!(self, "clamp_max", grad*{a}.is_smaller({b})); //Not complete
+        // At a bound the gradient goes to the clamped value, like in other frameworks, see strict_clamp
+        add_diff!(self, "clamp", grad*{a}.is_between({b}, {c}), grad*{a}.is_smaller({b}), grad*{a}.is_bigger({c}));
+        add_diff!(self, "clamp_min", grad*{a}.is_bigger_or_equal({b}), grad*{a}.is_smaller({b}));
+        add_diff!(self, "clamp_max", grad*{a}.is_smaller_or_equal({b}), grad*{a}.is_bigger({b}));
         add_diff!(self, "clone", grad);
-        add_diff!(self, "powi", grad*{b}*({a}.powi({b-1}))); // Not complete: grad*{a}.ln()*({a}.powi({b}))
-        add_diff!(self, "powf", grad*{b}*({a}.powf({b-1}))); // Not complete: grad*{a}.ln()*({a}.powf({b}))
+        add_diff!(self, "transpose", grad.transpose());
+        // Masks are piecewise constant, so no gradient flows through them.
+        // is_between includes the bounds, is_strictly_between, is_bigger and is_smaller do not
+        add_diff!(self, "is_between");
+        add_diff!(self, "is_strictly_between");
+        add_diff!(self, "is_bigger");
+        add_diff!(self, "is_smaller");
+        add_diff!(self, "is_bigger_or_equal");
+        add_diff!(self, "is_smaller_or_equal");
+        // An integer exponent has no gradient. It scales the gradient of the base as an integer, a borrowed exponent is
+        // copied and the conversion is left to the tensor type
+        add_diff!(self, "powi", grad*{a}.powi({b-1})*{b}.clone());
+        add_diff!(self, "powf", grad*{b}*{a}.powf({b-1.}), grad*{a}.ln()*{a}.powf({b}));
+        // Only the looked up rows of a table get a gradient, so it is kept sparse. The indices are not differentiable
+        // and are borrowed, whether the solver holds them by value or by reference
+        add_diff!(self, "embedding", {a}.sparse_rows(&{b}, grad));
+        add_diff!(self, "index_select", {a}.sparse_index({b}, &{c}, grad));
+        // The table only gives the shape of a sparse gradient, the rows are looked up again for the second order
+        add_diff!(self, "sparse_rows", 0., 0., grad.embedding(&{b}));
+        add_diff!(self, "sparse_index", 0., 0., 0., grad.index_select({b}, &{c}));
+        // Reductions over an axis: {b} is the axis and {c} is true when the axis is kept. The gradient gets the axis
+        // back with restore_dim and is broadcasted to the shape of the operand. max and min only pass it to the first
+        // largest or smallest element along the axis
+        add_diff!(self, "sum/2", grad.restore_dim({b}, {c})*{a}.ones_like());
+        add_diff!(self, "mean/2", grad.restore_dim({b}, {c})*{a}.reduction_weights({b}, 0.));
+        add_diff!(self, "prod/2", grad.restore_dim({b}, {c})*{out}.restore_dim({b}, {c})/{a});
+        add_diff!(self, "max/2", grad.restore_dim({b}, {c})*{a}.argmax_mask({b}));
+        add_diff!(self, "min/2", grad.restore_dim({b}, {c})*{a}.argmin_mask({b}));
+        // var and std are unbiased, like in other frameworks
+        add_diff!(self, "var/2", grad.restore_dim({b}, {c})*2.*({a}-{a}.mean({b}, true))*{a}.reduction_weights({b}, 1.));
+        add_diff!(self, "std/2", grad.restore_dim({b}, {c})*({a}-{a}.mean({b}, true))*{a}.reduction_weights({b}, 1.)/{out}.restore_dim({b}, {c}));
+        add_diff!(self, "restore_dim/2", grad.sum({b}, {c}));
+        // Shape operations only move values, so the gradient is moved back. {a}.shape() is the shape of the operand,
+        // stored by the forward pass when the operand is an intermediate
+        add_diff!(self, "reshape", grad.reshape({a}.shape()));
+        add_diff!(self, "view", grad.reshape({a}.shape()));
+        add_diff!(self, "flatten", grad.reshape({a}.shape()));
+        add_diff!(self, "squeeze", grad.reshape({a}.shape()));
+        add_diff!(self, "unsqueeze", grad.squeeze({b}));
+        add_diff!(self, "permute", grad.permute_inverse({b}));
+        add_diff!(self, "permute_inverse", grad.permute({b}));
+        add_diff!(self, "transpose/2", grad.transpose({b}, {c}));
+        add_diff!(self, "broadcast_to", grad.sum_to({a}.shape()));
+        add_diff!(self, "sum_to", grad.broadcast_to({a}.shape()));
+        add_diff!(self, "shape");
+        // A list of tensors has a rule for every tensor in it, see get_expressions. A part of a list, like an output
+        // of split, gets the gradient in its place and zeros for the other parts
+        add_diff!(self, "index", grad.as_part({b}, &{a}));
+        add_diff!(self, "as_part", grad[{b}].clone());
+        add_diff!(self, "cat", grad.split_like(&{a}, {b}));
+        add_diff!(self, "split_like", Tensor::cat(&grad, {c}));
+        add_diff!(self, "stack", grad.unstack({b}));
+        add_diff!(self, "unstack", Tensor::stack(&grad, {b}));
+        add_diff!(self, "split", Tensor::cat(&grad, {c}));
+        add_diff!(self, "chunk", Tensor::cat(&grad, {c}));
+        // The stable forms, written with the output instead of exponentials of the operand
+        add_diff!(self, "softmax", grad*{out}-(grad*{out}).sum({b}, true)*{out});
+        add_diff!(self, "log_softmax", grad-{out}.exp()*grad.sum({b}, true));
+        add_diff!(self, "logsumexp", grad.restore_dim({b}, {c})*({a}-{out}.restore_dim({b}, {c})).exp());
+        // Only depend on the shape or the position of values
+        add_diff!(self, "ones_like");
+        add_diff!(self, "reduction_weights");
+        add_diff!(self, "argmax_mask");
+        add_diff!(self, "argmin_mask");
+        add_diff!(self, "erf", grad*1.1283791670955126*(-{a}.powi(2)).exp());
+        // Activations reuse their output where the derivative can be written with it. Slopes and bounds of
+        // activations are constants
+        add_diff!(self, "relu", grad*{out}.is_bigger(0.));
+        add_diff!(self, "leaky_relu", grad*({a}.is_bigger(0.)*(1.-{b}) + {b}));
+        add_diff!(self, "sigmoid", grad*{out}*(1.-{out}));
+        add_diff!(self, "softplus", grad*{a}.sigmoid());
+        add_diff!(self, "gelu", grad*(0.5*(1.+({a}*0.7071067811865476).erf()) + {a}*(-{a}.powi(2)*0.5).exp()*0.3989422804014327));
+        add_diff!(self, "gelu_tanh", grad*(0.5*(1.+(0.7978845608028654*({a}+0.044715*{a}.powi(3))).tanh())
+            + 0.5*{a}*(1.-(0.7978845608028654*({a}+0.044715*{a}.powi(3))).tanh().powi(2))*0.7978845608028654*(1.+0.134145*{a}.powi(2))));
+        add_diff!(self, "silu", grad*{a}.sigmoid()*(1.+{a}*(1.-{a}.sigmoid())));
+        add_diff!(self, "swish", grad*{a}.sigmoid()*(1.+{a}*(1.-{a}.sigmoid())));
+        add_diff!(self, "elu", grad*({a}.is_bigger(0.) + {a}.is_smaller_or_equal(0.)*({out}+{b})));
+        add_diff!(self, "selu", grad*({a}.is_bigger(0.)*1.0507009873554805 + {a}.is_smaller_or_equal(0.)*({out}+1.7580993408473766)));
+        add_diff!(self, "hardtanh", grad*{a}.is_strictly_between({b}, {c}));
+        add_diff!(self, "mish", grad*({a}.softplus().tanh() + {a}*(1.-{a}.softplus().tanh().powi(2))*{a}.sigmoid()));
+    }
+
+    /// At a bound of a clamp the gradient goes to the bound instead of the clamped value.
+    pub fn strict_clamp(&mut self) {
+        add_diff!(self, "clamp", grad*{a}.is_strictly_between({b}, {c}), grad*{a}.is_smaller_or_equal({b}), grad*{a}.is_bigger_or_equal({c}));
+        add_diff!(self, "clamp_min", grad*{a}.is_bigger({b}), grad*{a}.is_smaller_or_equal({b}));
+        add_diff!(self, "clamp_max", grad*{a}.is_smaller({b}), grad*{a}.is_bigger_or_equal({b}));
     }
-}
\ No newline at end of file
+
+    /// Forward-mode version of `strict_clamp`.
+    pub fn strict_clamp_forward(&mut self) {
+        add_diff!(self, "clamp", tangent*{a}.is_strictly_between({b}, {c}), tangent*{a}.is_smaller_or_equal({b}), tangent*{a}.is_bigger_or_equal({c}));
+        add_diff!(self, "clamp_min", tangent*{a}.is_bigger({b}), tangent*{a}.is_smaller_or_equal({b}));
+        add_diff!(self, "clamp_max", tangent*{a}.is_smaller({b}), tangent*{a}.is_bigger_or_equal({b}));
+    }
+
+    pub fn init_forward(&mut self) {
+        // The operands are borrowed in forward mode, so rules can use them more than once
+        add_diff!(self, "add", tangent, tangent);
+        add_diff!(self, "sub", tangent, -tangent);
+        add_diff!(self, "mul", tangent*{b}, {a}*tangent);
+        add_diff!(self, "matmul", tangent.matmul(&{b}), {a}.matmul(&tangent));
+        add_diff!(self, "bmm", tangent.bmm(&{b}), {a}.bmm(&tangent));
+        add_diff!(self, "dot", tangent.dot(&{b}), {a}.dot(&tangent));
+        add_diff!(self, "div", tangent/{b}, -({a}*tangent)/({b}).powi(2));
+        add_diff!(self, "neg", -tangent);
+        add_diff!(self, "sin", tangent*{a}.cos());
+        add_diff!(self, "cos", tangent*(-{a}.sin()));
+        add_diff!(self, "tan", tangent/({a}.cos()).powi(2));
+        add_diff!(self, "sinh", tangent*{a}.cosh());
+        add_diff!(self, "cosh", tangent*{a}.sinh());
+        add_diff!(self, "tanh", tangent/({a}.cosh()).powi(2));
+        add_diff!(self, "exp", tangent*{a}.exp());
+        add_diff!(self, "ln", tangent/{a});
+        add_diff!(self, "clamp", tangent*{a}.is_between({b}, {c}), tangent*{a}.is_smaller({b}), tangent*{a}.is_bigger({c}));
+        add_diff!(self, "clamp_min", tangent*{a}.is_bigger_or_equal({b}), tangent*{a}.is_smaller({b}));
+        add_diff!(self, "clamp_max", tangent*{a}.is_smaller_or_equal({b}), tangent*{a}.is_bigger({b}));
+        add_diff!(self, "clone", tangent);
+        add_diff!(self, "transpose", tangent.transpose());
+        add_diff!(self, "is_between");
+        add_diff!(self, "is_strictly_between");
+        add_diff!(self, "is_bigger");
+        add_diff!(self, "is_smaller");
+        add_diff!(self, "is_bigger_or_equal");
+        add_diff!(self, "is_smaller_or_equal");
+        add_diff!(self, "powi", tangent*{a}.powi({b-1})*{b}.clone());
+        add_diff!(self, "powf", tangent*{b}*{a}.powf({b-1.}), tangent*{a}.ln()*{a}.powf({b}));
+        add_diff!(self, "embedding", tangent.embedding(&{b}));
+        add_diff!(self, "index_select", tangent.index_select({b}, &{c}));
+        add_diff!(self, "sparse_rows", 0., 0., {a}.sparse_rows(&{b}, tangent.clone()));
+        add_diff!(self, "sparse_index", 0., 0., 0., {a}.sparse_index({b}, &{c}, tangent.clone()));
+        add_diff!(self, "sum/2", tangent.sum({b}, {c}));
+        add_diff!(self, "mean/2", tangent.mean({b}, {c}));
+        add_diff!(self, "prod/2", (tangent*{out}.restore_dim({b}, {c})/{a}).sum({b}, {c}));
+        add_diff!(self, "max/2", (tangent*{a}.argmax_mask({b})).sum({b}, {c}));
+        add_diff!(self, "min/2", (tangent*{a}.argmin_mask({b})).sum({b}, {c}));
+        add_diff!(self, "var/2", (tangent*2.*({a}-{a}.mean({b}, true))*{a}.reduction_weights({b}, 1.)).sum({b}, {c}));
+        add_diff!(self, "std/2", (tangent*({a}-{a}.mean({b}, true))*{a}.reduction_weights({b}, 1.)).sum({b}, {c})/{out});
+        add_diff!(self, "restore_dim/2", tangent.restore_dim({b}, {c}));
+        add_diff!(self, "reshape", tangent.reshape({b}));
+        add_diff!(self, "view", tangent.reshape({b}));
+        add_diff!(self, "flatten", tangent.reshape({out}.shape()));
+        add_diff!(self, "squeeze", tangent.reshape({out}.shape()));
+        add_diff!(self, "unsqueeze", tangent.unsqueeze({b}));
+        add_diff!(self, "permute", tangent.permute({b}));
+        add_diff!(self, "permute_inverse", tangent.permute_inverse({b}));
+        add_diff!(self, "transpose/2", tangent.transpose({b}, {c}));
+        add_diff!(self, "broadcast_to", tangent.broadcast_to({b}));
+        add_diff!(self, "sum_to", tangent.sum_to({b}));
+        add_diff!(self, "shape");
+        // The solver builds the tangent of a list. Only the first operand of these operations has a tangent
+        add_diff!(self, "index", tangent[{b}].clone());
+        add_diff!(self, "as_part", tangent.as_part({b}, {c}));
+        add_diff!(self, "cat", Tensor::cat(tangent, {b}));
+        add_diff!(self, "split_like", tangent.split_like({b}, {c}));
+        add_diff!(self, "stack", Tensor::stack(tangent, {b}));
+        add_diff!(self, "unstack", tangent.unstack({b}));
+        add_diff!(self, "split", tangent.split({b}, {c}));
+        add_diff!(self, "chunk", tangent.chunk({b}, {c}));
+        add_diff!(self, "softmax", tangent*{out}-(tangent*{out}).sum({b}, true)*{out});
+        add_diff!(self, "log_softmax", tangent-({out}.exp()*tangent).sum({b}, true));
+        add_diff!(self, "logsumexp", (tangent*({a}-{out}.restore_dim({b}, {c})).exp()).sum({b}, {c}));
+        add_diff!(self, "ones_like");
+        add_diff!(self, "reduction_weights");
+        add_diff!(self, "argmax_mask");
+        add_diff!(self, "argmin_mask");
+        add_diff!(self, "erf", tangent*1.1283791670955126*(-{a}.powi(2)).exp());
+        add_diff!(self, "relu", tangent*{out}.is_bigger(0.));
+        add_diff!(self, "leaky_relu", tangent*({a}.is_bigger(0.)*(1.-{b}) + {b}));
+        add_diff!(self, "sigmoid", tangent*{out}*(1.-{out}));
+        add_diff!(self, "softplus", tangent*{a}.sigmoid());
+        add_diff!(self, "gelu", tangent*(0.5*(1.+({a}*0.7071067811865476).erf()) + {a}*(-{a}.powi(2)*0.5).exp()*0.3989422804014327));
+        add_diff!(self, "gelu_tanh", tangent*(0.5*(1.+(0.7978845608028654*({a}+0.044715*{a}.powi(3))).tanh())
+            + 0.5*{a}*(1.-(0.7978845608028654*({a}+0.044715*{a}.powi(3))).tanh().powi(2))*0.7978845608028654*(1.+0.134145*{a}.powi(2))));
+        add_diff!(self, "silu", tangent*{a}.sigmoid()*(1.+{a}*(1.-{a}.sigmoid())));
+        add_diff!(self, "swish", tangent*{a}.sigmoid()*(1.+{a}*(1.-{a}.sigmoid())));
+        add_diff!(self, "elu", tangent*({a}.is_bigger(0.) + {a}.is_smaller_or_equal(0.)*({out}+{b})));
+        add_diff!(self, "selu", tangent*({a}.is_bigger(0.)*1.0507009873554805 + {a}.is_smaller_or_equal(0.)*({out}+1.7580993408473766)));
+        add_diff!(self, "hardtanh", tangent*{a}.is_strictly_between({b}, {c}));
+        add_diff!(self, "mish", tangent*({a}.softplus().tanh() + {a}*(1.-{a}.softplus().tanh().powi(2))*{a}.sigmoid()));
+    }
+}
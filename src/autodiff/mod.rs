@@ -1,2 +1,4 @@
 pub mod autodiff;
-pub mod solver;
\ No newline at end of file
+pub mod solver;
+pub mod simplifier;
+pub mod rule_file;
@@ -0,0 +1,236 @@
+use proc_macro2::TokenStream;
+use quote::quote;
+use syn::{Expr, Ident};
+use std::path::{Path, PathBuf};
+use std::fs;
+use toml::Value;
+
+pub static RULE_FILE: &str = "raise-graph.toml";
+pub static OPERATORS: &[&str] = &["add", "sub", "mul", "div"];
+
+/// Derivative rules of a project, read from `raise-graph.toml` in the crate or one of the directories above it up to
+/// the root of the workspace, so a file in the root of a workspace is used by every crate in it.
+///
+/// Every rule is a string in the format of `AutoDiff::add_diff`, with a list for methods with more operands. Rules
+/// for `jvp` and `hvp` are written with the tangent of the operand:
+///
+/// ```toml
+/// [diff]
+/// my_act = "grad * {a}.my_act_deriv()"
+/// my_op = ["grad * {b}", "{a} * grad"]
+///
+/// [forward_diff]
+/// my_act = "tangent * {a}.my_act_deriv()"
+/// ```
+///
+/// The operations operators are read as can be changed for the tensor type of the project, like `*` when it is a
+/// matrix product:
+///
+/// ```toml
+/// [operators]
+/// mul = "matmul"
+/// ```
+pub struct RuleFile {
+    pub path: PathBuf,
+    pub rules: Vec<(String, Vec<TokenStream>)>,
+    pub forward_rules: Vec<(String, Vec<TokenStream>)>,
+    pub operators: Vec<(String, String)>,
+}
+
+impl RuleFile {
+    pub fn load() -> Option<RuleFile> {
+        let dir = std::env::var("CARGO_MANIFEST_DIR").ok()?;
+        let path = RuleFile::find(Path::new(&dir))?;
+        let content = match fs::read_to_string(&path) {
+            Ok(content) => content,
+            Err(e) => RuleFile::error(&path, None, &e.to_string())
+        };
+        match RuleFile::parse(&content) {
+            Ok(mut rule_file) => {
+                rule_file.path = path;
+                Some(rule_file)
+            }
+            Err((line, message)) => RuleFile::error(&path, line, &message)
+        }
+    }
+
+    // The directories of the crate up to the root of its workspace, a crate outside of a workspace is its own root
+    fn find(dir: &Path) -> Option<PathBuf> {
+        let root = dir.ancestors()
+            .find(|dir| RuleFile::is_workspace(dir))
+            .unwrap_or(dir);
+        dir.ancestors()
+            .take_while(|dir| dir.starts_with(root))
+            .map(|dir| dir.join(RULE_FILE))
+            .find(|path| path.is_file())
+    }
+
+    fn is_workspace(dir: &Path) -> bool {
+        fs::read_to_string(dir.join("Cargo.toml")).ok()
+            .and_then(|manifest| manifest.parse::<Value>().ok())
+            .is_some_and(|manifest| manifest.get("workspace").is_some())
+    }
+
+    // There is no span in the file, so the error is reported on the attribute with the file and line in the message
+    fn error(path: &Path, line: Option<usize>, message: &str) -> ! {
+        let message = match line {
+            Some(line) => format!("{}:{}: {}", path.display(), line, message),
+            None => format!("{}: {}", path.display(), message)
+        };
+        proc_macro::Span::call_site().error(&message).emit();
+        panic!("{}", message)
+    }
+
+    // Errors come with the line they are on, when it is known
+    fn parse(content: &str) -> Result<RuleFile, (Option<usize>, String)> {
+        let file = content.parse::<Value>().map_err(|e| (e.line_col().map(|(line, _)| line + 1), e.to_string()))?;
+        let mut rules = Vec::new();
+        let mut forward_rules = Vec::new();
+        let mut operators = Vec::new();
+        for (section, entries) in file.as_table().unwrap() {
+            let entries = entries.as_table()
+                .ok_or_else(|| (RuleFile::line(content, "", Some(section)), format!("`{}` is not a section.", section)))?;
+            if !["diff", "forward_diff", "operators"].contains(&section.as_str()) {
+                let message = format!("Unknown section `{}`, expected `diff`, `forward_diff` or `operators`.", section);
+                return Err((RuleFile::line(content, section, None), message));
+            }
+            for (name, value) in entries {
+                let parsed = match section.as_str() {
+                    "diff" => RuleFile::parse_rule(name, value).map(|rule| rules.push(rule)),
+                    "forward_diff" => RuleFile::parse_rule(name, value).map(|rule| forward_rules.push(rule)),
+                    _ => RuleFile::parse_operator(name, value).map(|operator| operators.push(operator))
+                };
+                parsed.map_err(|message| (RuleFile::line(content, section, Some(name)), message))?;
+            }
+        }
+        Ok(RuleFile { path: PathBuf::new(), rules, forward_rules, operators })
+    }
+
+    // Values read by TOML have no position, so the line of a key is found in the text of its section. Without a key
+    // it is the line of the section
+    fn line(content: &str, section: &str, key: Option<&str>) -> Option<usize> {
+        let mut current = "";
+        for (i, line) in content.lines().enumerate() {
+            let line = line.trim();
+            if let Some(header) = line.strip_prefix('[').and_then(|line| line.strip_suffix(']')) {
+                current = header.trim();
+                if key.is_none() && current == section {
+                    return Some(i + 1);
+                }
+            } else if let (Some(key), Some((name, _))) = (key, line.split_once('=')) {
+                if current == section && name.trim().trim_matches('"') == key {
+                    return Some(i + 1);
+                }
+            }
+        }
+        None
+    }
+
+    fn parse_rule(method: &str, value: &Value) -> Result<(String, Vec<TokenStream>), String> {
+        syn::parse_str::<Ident>(method).map_err(|_| format!("`{}` is not a method name.", method))?;
+        let rules = match value {
+            Value::Array(rules) => rules.iter().collect(),
+            rule => vec![rule]
+        };
+        let mut expressions = Vec::new();
+        for rule in rules {
+            let rule = rule.as_str().ok_or(format!("The rules of `{}` are not strings.", method))?;
+            let expr = syn::parse_str::<Expr>(rule).map_err(|e| format!("Invalid rule for `{}`: {}", method, e))?;
+            expressions.push(quote!(#expr));
+        }
+        Ok((method.to_string(), expressions))
+    }
+
+    fn parse_operator(operator: &str, value: &Value) -> Result<(String, String), String> {
+        if !OPERATORS.contains(&operator) {
+            return Err(format!("`{}` is not an operator, expected one of {}.", operator, OPERATORS.join(", ")));
+        }
+        let method = value.as_str().ok_or(format!("The method of `{}` is not a string.", operator))?;
+        syn::parse_str::<Ident>(method).map_err(|_| format!("`{}` is not a method name.", method))?;
+        Ok((operator.to_string(), method.to_string()))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn rule(method: &str, toml: &str) -> Result<(String, Vec<String>), String> {
+        let value = format!("value = {}", toml).parse::<Value>().unwrap();
+        RuleFile::parse_rule(method, &value["value"])
+            .map(|(method, rules)| (method, rules.iter().map(|rule| rule.to_string()).collect()))
+    }
+
+    #[test]
+    fn parse_rule() {
+        assert_eq!(rule("my_act", r#""grad * {a}.my_act_deriv()""#).unwrap().1.len(), 1);
+        let (method, rules) = rule("my_op", r#"["grad * {b}", "{a} * grad"]"#).unwrap();
+        assert_eq!(method, "my_op");
+        assert_eq!(rules, vec![quote!(grad * {b}).to_string(), quote!({a} * grad).to_string()]);
+        assert!(rule("my op", r#""grad""#).is_err());
+        assert!(rule("my_op", r#""grad *""#).is_err());
+        assert!(rule("my_op", "[1, 2]").is_err());
+        // Strings are read by TOML, so quotes in a rule are escaped
+        assert_eq!(rule("my_op", r#""grad.named(\"a\")""#).unwrap().1, vec![quote!(grad.named("a")).to_string()]);
+    }
+
+    #[test]
+    fn parse_operator() {
+        let value = Value::String("matmul".to_string());
+        assert_eq!(RuleFile::parse_operator("mul", &value), Ok(("mul".to_string(), "matmul".to_string())));
+        assert!(RuleFile::parse_operator("pow", &value).is_err());
+        assert!(RuleFile::parse_operator("mul", &Value::String("a.matmul".to_string())).is_err());
+        assert!(RuleFile::parse_operator("mul", &Value::Integer(1)).is_err());
+    }
+
+    #[test]
+    fn find() {
+        let root = std::env::temp_dir().join(format!("raise-graph-find-{}", std::process::id()));
+        let workspace = root.join("workspace");
+        let member = workspace.join("member");
+        fs::create_dir_all(&member).unwrap();
+        fs::write(workspace.join("Cargo.toml"), "[workspace]\nmembers = [\"member\"]\n").unwrap();
+        fs::write(member.join("Cargo.toml"), "[package]\nname = \"member\"\n").unwrap();
+
+        // A file above the root of the workspace is not used
+        fs::write(root.join(RULE_FILE), "").unwrap();
+        assert_eq!(RuleFile::find(&member), None);
+        fs::write(workspace.join(RULE_FILE), "").unwrap();
+        assert_eq!(RuleFile::find(&member), Some(workspace.join(RULE_FILE)));
+        fs::write(member.join(RULE_FILE), "").unwrap();
+        assert_eq!(RuleFile::find(&member), Some(member.join(RULE_FILE)));
+        fs::remove_dir_all(&root).unwrap();
+    }
+
+    #[test]
+    fn parse() {
+        let rule_file = RuleFile::parse(r#"
+            # Rules of the project
+            [diff]
+            my_act = "grad * {a}.my_act_deriv()"
+
+            [forward_diff]
+            my_act = "tangent * {a}.my_act_deriv()"
+            my_act_deriv = "tangent * {a}.my_act_deriv2()"
+
+            [operators]
+            mul = "matmul"
+        "#).unwrap();
+        assert_eq!(rule_file.rules.len(), 1);
+        assert_eq!(rule_file.forward_rules.len(), 2);
+        assert_eq!(rule_file.operators, vec![("mul".to_string(), "matmul".to_string())]);
+        assert!(RuleFile::parse("[rules]\nmy_act = \"grad\"").is_err());
+        assert!(RuleFile::parse("[diff]\nmy_act = grad").is_err());
+    }
+
+    #[test]
+    fn error_lines() {
+        let line = |content: &str| RuleFile::parse(content).err().unwrap().0;
+        assert_eq!(line("[diff]\nmy_act = \"grad\"\nmy_op = \"grad *\""), Some(3));
+        assert_eq!(line("[operators]\n\n\"mul\" = \"a.matmul\""), Some(3));
+        assert_eq!(line("[diff]\nmy_act = \"grad\"\n\n[rules]\nmy_op = \"grad\""), Some(4));
+        assert_eq!(line("[diff]\nmy_act = grad"), Some(2));
+        // The same key in another section is not the one with the error
+        assert_eq!(line("[forward_diff]\nmy_op = \"tangent\"\n[diff]\nmy_op = \"grad *\""), Some(4));
+    }
+}
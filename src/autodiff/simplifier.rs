@@ -0,0 +1,441 @@
+use syn::fold::{self, Fold};
+use syn::*;
+use proc_macro2::Span;
+use quote::{quote, format_ident};
+use std::collections::HashMap;
+
+// Numeric value of a literal operand, used for constant folding
+#[derive(Clone, Copy)]
+enum Number {
+    Int(i128),
+    Float(f64),
+}
+
+pub struct Simplifier {
+    substitutions: HashMap<String, Expr>,
+    shapes: HashMap<String, Expr>,
+    owned: Vec<String>,
+}
+
+impl Simplifier {
+    pub fn new() -> Simplifier {
+        Simplifier {
+            substitutions: HashMap::new(),
+            shapes: HashMap::new(),
+            owned: vec!["grad".to_string()],
+        }
+    }
+
+    /// Substitute an expression for a variable, like a literal operand instead of binding it.
+    pub fn substitute(&mut self, name: &str, expr: Expr) {
+        self.substitutions.insert(name.to_string(), expr);
+    }
+
+    /// Substitute an expression for the shape of a variable, like a shape stored by the forward pass.
+    pub fn substitute_shape(&mut self, name: &str, expr: Expr) {
+        self.shapes.insert(name.to_string(), expr);
+    }
+
+    /// Mark a variable as owned by the expressions, so it can be moved into its last use.
+    pub fn add_owned(&mut self, name: &str) {
+        self.owned.push(name.to_string());
+    }
+
+    pub fn simplify(&mut self, expr: Expr) -> Expr {
+        self.fold_expr(expr)
+    }
+
+    /// Folds an operand to a number if it is constant, like the exponent `(2 - 1)` in a derivative.
+    pub fn constant(expr: Expr) -> Option<Expr> {
+        let folded = Simplifier::new().simplify(expr);
+        Simplifier::number(&folded).map(|_| folded)
+    }
+
+    /// Move every owned variable into its last use and borrow it everywhere before that.
+    pub fn assign_ownership(&self, exprs: &mut [Expr]) {
+        for var in &self.owned {
+            Simplifier::assign_ownership_of(exprs, var);
+        }
+    }
+
+    fn assign_ownership_of(exprs: &mut [Expr], var: &str) {
+        let mut counter = UseCounter { var, uses: 0 };
+        for expr in exprs.iter() {
+            counter.fold_expr(expr.clone());
+        }
+        let mut ownership = Ownership { var, uses: counter.uses, seen: 0 };
+        for expr in exprs.iter_mut() {
+            // A result has to be owned, so a plain copy of the variable is a clone unless it is the last use
+            if ownership.is_var(expr) {
+                if !ownership.is_last() {
+                    let var = format_ident!("{}", var);
+                    *expr = parse_quote!(#var.clone());
+                }
+                continue;
+            }
+            *expr = ownership.fold_expr(expr.clone());
+        }
+    }
+
+    fn number(expr: &Expr) -> Option<Number> {
+        match expr {
+            Expr::Lit(i) => match &i.lit {
+                Lit::Int(li) => li.base10_parse::<i128>().ok().map(Number::Int),
+                Lit::Float(li) => li.base10_parse::<f64>().ok().map(Number::Float),
+                _ => None
+            }
+            Expr::Unary(i) => match (i.op, Simplifier::number(&i.expr)) {
+                (UnOp::Neg(_), Some(Number::Int(n))) => Some(Number::Int(-n)),
+                (UnOp::Neg(_), Some(Number::Float(n))) => Some(Number::Float(-n)),
+                _ => None
+            }
+            Expr::Paren(i) => Simplifier::number(&i.expr),
+            _ => None
+        }
+    }
+
+    fn is_number(expr: &Expr, value: f64) -> bool {
+        match Simplifier::number(expr) {
+            Some(Number::Int(n)) => n as f64 == value,
+            Some(Number::Float(n)) => n == value,
+            None => false
+        }
+    }
+
+    fn suffix(expr: &Expr) -> String {
+        match expr {
+            Expr::Lit(ExprLit { lit: Lit::Int(li), .. }) => li.suffix().to_string(),
+            Expr::Lit(ExprLit { lit: Lit::Float(li), .. }) => li.suffix().to_string(),
+            Expr::Unary(i) => Simplifier::suffix(&i.expr),
+            Expr::Paren(i) => Simplifier::suffix(&i.expr),
+            _ => String::new()
+        }
+    }
+
+    fn to_expr(number: Number, suffix: &str) -> Expr {
+        let (negative, lit) = match number {
+            Number::Int(n) => (n < 0, Lit::Int(LitInt::new(&(n.abs().to_string() + suffix), Span::call_site()))),
+            Number::Float(n) => {
+                // Debug formatting always keeps a decimal point or exponent, so the literal stays a float
+                let text = format!("{:?}", n.abs());
+                (n < 0., Lit::Float(LitFloat::new(&(text + suffix), Span::call_site())))
+            }
+        };
+        let lit = Expr::Lit(ExprLit { attrs: Vec::new(), lit });
+        if negative {
+            parse_quote!(-#lit)
+        } else {
+            lit
+        }
+    }
+
+    fn fold_constants(left: Number, op: &BinOp, right: Number) -> Option<Number> {
+        match (left, right) {
+            (Number::Int(l), Number::Int(r)) => match op {
+                BinOp::Add(_) => l.checked_add(r),
+                BinOp::Sub(_) => l.checked_sub(r),
+                BinOp::Mul(_) => l.checked_mul(r),
+                // Integer division truncates, only fold when exact
+                BinOp::Div(_) if r != 0 && l % r == 0 => Some(l / r),
+                _ => None
+            }.map(Number::Int),
+            (l, r) => {
+                let (l, r) = (Simplifier::as_float(l), Simplifier::as_float(r));
+                match op {
+                    BinOp::Add(_) => Some(l + r),
+                    BinOp::Sub(_) => Some(l - r),
+                    BinOp::Mul(_) => Some(l * r),
+                    BinOp::Div(_) if r != 0. => Some(l / r),
+                    _ => None
+                }.map(Number::Float)
+            }
+        }
+    }
+
+    fn as_float(number: Number) -> f64 {
+        match number {
+            Number::Int(n) => n as f64,
+            Number::Float(n) => n
+        }
+    }
+
+    fn simplify_binary(&self, i: ExprBinary) -> Expr {
+        if let (Some(l), Some(r)) = (Simplifier::number(&i.left), Simplifier::number(&i.right)) {
+            if let Some(n) = Simplifier::fold_constants(l, &i.op, r) {
+                let mut suffix = Simplifier::suffix(&i.left);
+                if suffix.is_empty() {
+                    suffix = Simplifier::suffix(&i.right);
+                }
+                return Simplifier::to_expr(n, &suffix);
+            }
+        }
+        let simplified = match i.op {
+            BinOp::Add(_) if Simplifier::is_number(&i.right, 0.) => *i.left,
+            BinOp::Add(_) if Simplifier::is_number(&i.left, 0.) => *i.right,
+            BinOp::Sub(_) if Simplifier::is_number(&i.right, 0.) => *i.left,
+            BinOp::Sub(_) if Simplifier::is_number(&i.left, 0.) => {
+                let right = i.right;
+                self.simplify_unary(parse_quote!(-#right))
+            }
+            BinOp::Mul(_) if Simplifier::is_number(&i.right, 1.) => *i.left,
+            BinOp::Mul(_) if Simplifier::is_number(&i.left, 1.) => *i.right,
+            BinOp::Div(_) if Simplifier::is_number(&i.right, 1.) => *i.left,
+            _ => return Expr::Binary(i)
+        };
+        Simplifier::owned(simplified)
+    }
+
+    // An operator always produces an owned value, so a borrowed operand left over must be cloned
+    fn owned(expr: Expr) -> Expr {
+        let mut inner = &expr;
+        while let Expr::Paren(p) = inner {
+            inner = &p.expr;
+        }
+        if let Expr::Reference(r) = inner {
+            let referenced = &r.expr;
+            return parse_quote!(#referenced.clone());
+        }
+        expr
+    }
+
+    fn simplify_unary(&self, i: ExprUnary) -> Expr {
+        if let UnOp::Neg(_) = i.op {
+            if let Some(n) = Simplifier::number(&i.expr) {
+                let negated = match n {
+                    Number::Int(n) => Number::Int(-n),
+                    Number::Float(n) => Number::Float(-n),
+                };
+                return Simplifier::to_expr(negated, &Simplifier::suffix(&i.expr));
+            }
+            // -(-x) = x
+            let mut inner = &*i.expr;
+            while let Expr::Paren(p) = inner {
+                inner = &p.expr;
+            }
+            if let Expr::Unary(ExprUnary { op: UnOp::Neg(_), expr, .. }) = inner {
+                return Simplifier::owned((**expr).clone());
+            }
+        }
+        Expr::Unary(i)
+    }
+
+    fn simplify_method_call(&self, i: ExprMethodCall) -> Expr {
+        let method = i.method.to_string();
+        // A number is its own copy and transpose
+        if (method == "clone" || method == "transpose") && i.args.is_empty() && Simplifier::number(&i.receiver).is_some() {
+            return *i.receiver;
+        }
+        if (method == "powi" || method == "powf") && i.args.len() == 1 {
+            if Simplifier::is_number(&i.args[0], 1.) {
+                return Simplifier::owned(*i.receiver);
+            }
+            if let (Some(base), Some(exp)) = (Simplifier::number(&i.receiver), Simplifier::number(&i.args[0])) {
+                let (base, exp) = (Simplifier::as_float(base), Simplifier::as_float(exp));
+                let value = if method == "powi" { base.powi(exp as i32) } else { base.powf(exp) };
+                if value.is_finite() {
+                    return Simplifier::to_expr(Number::Float(value), &Simplifier::suffix(&i.receiver));
+                }
+            }
+        }
+        Expr::MethodCall(i)
+    }
+
+    // Converts a number to a float or integer type, like the integer exponent of powi scaling a gradient
+    fn simplify_cast(&self, i: ExprCast) -> Expr {
+        let ty = &i.ty;
+        let ty = quote!(#ty).to_string();
+        match (Simplifier::number(&i.expr), ty.as_str()) {
+            (Some(n), "f32") | (Some(n), "f64") => Simplifier::to_expr(Number::Float(Simplifier::as_float(n)), &ty),
+            (Some(Number::Int(n)), "i32") | (Some(Number::Int(n)), "i64") => Simplifier::to_expr(Number::Int(n), &ty),
+            _ => Expr::Cast(i)
+        }
+    }
+
+    fn simplify_paren(&self, i: ExprParen) -> Expr {
+        // Parentheses are only needed around expressions that bind weaker than a method call
+        match *i.expr {
+            Expr::Path(_) | Expr::Paren(_) | Expr::MethodCall(_) | Expr::Field(_) | Expr::Call(_) => *i.expr,
+            Expr::Lit(_) => *i.expr,
+            _ => Expr::Paren(i)
+        }
+    }
+}
+
+impl Fold for Simplifier {
+    fn fold_expr(&mut self, ii: Expr) -> Expr {
+        if let Expr::Path(i) = &ii {
+            if let Some(ident) = i.path.get_ident() {
+                if let Some(expr) = self.substitutions.get(&ident.to_string()) {
+                    return Simplifier::new().simplify(expr.clone());
+                }
+            }
+        }
+        if let Expr::MethodCall(i) = &ii {
+            if let Some(name) = variable(&i.receiver) {
+                if i.method == "shape" && i.args.is_empty() && self.shapes.contains_key(&name) {
+                    return self.shapes[&name].clone();
+                }
+            }
+        }
+        match fold::fold_expr(self, ii) {
+            Expr::Binary(i) => self.simplify_binary(i),
+            Expr::Unary(i) => self.simplify_unary(i),
+            Expr::MethodCall(i) => self.simplify_method_call(i),
+            Expr::Paren(i) => self.simplify_paren(i),
+            Expr::Cast(i) => self.simplify_cast(i),
+            // Numbers are Copy, a reference to one is never needed
+            Expr::Reference(i) if Simplifier::number(&i.expr).is_some() => *i.expr,
+            other => other
+        }
+    }
+}
+
+// Strips parentheses and returns the variable name if the expression is a plain variable
+fn variable(expr: &Expr) -> Option<String> {
+    match expr {
+        Expr::Paren(i) => variable(&i.expr),
+        Expr::Path(i) => i.path.get_ident().map(|ident| ident.to_string()),
+        _ => None
+    }
+}
+
+struct UseCounter<'a> {
+    var: &'a str,
+    uses: usize,
+}
+
+impl<'a> Fold for UseCounter<'a> {
+    fn fold_expr(&mut self, ii: Expr) -> Expr {
+        if let Expr::Path(_) = &ii {
+            if variable(&ii).is_some_and(|name| name == self.var) {
+                self.uses += 1;
+            }
+        }
+        fold::fold_expr(self, ii)
+    }
+}
+
+struct Ownership<'a> {
+    var: &'a str,
+    uses: usize,
+    seen: usize,
+}
+
+impl<'a> Ownership<'a> {
+    fn is_var(&self, expr: &Expr) -> bool {
+        variable(expr).is_some_and(|name| name == self.var)
+    }
+
+    fn is_last(&mut self) -> bool {
+        self.seen += 1;
+        self.seen == self.uses
+    }
+}
+
+impl<'a> Fold for Ownership<'a> {
+    fn fold_expr(&mut self, ii: Expr) -> Expr {
+        let var = format_ident!("{}", self.var);
+        match &ii {
+            // Explicit clones and borrows are only needed when the value is used again later
+            Expr::MethodCall(i) if i.method == "clone" && i.args.is_empty() && self.is_var(&i.receiver) => {
+                if self.is_last() { parse_quote!(#var) } else { ii }
+            }
+            Expr::Reference(i) if i.mutability.is_none() && self.is_var(&i.expr) => {
+                if self.is_last() { parse_quote!(#var) } else { ii }
+            }
+            Expr::Path(_) if self.is_var(&ii) => {
+                if self.is_last() { ii } else { parse_quote!((&#var)) }
+            }
+            // Arguments of calls keep their borrows, the called function decides the type
+            Expr::MethodCall(i) => {
+                let mut i = i.clone();
+                i.receiver = Box::new(self.fold_expr(*i.receiver));
+                i.args = i.args.into_iter().map(|arg| self.fold_argument(arg)).collect();
+                Expr::MethodCall(i)
+            }
+            Expr::Call(i) => {
+                let mut i = i.clone();
+                i.args = i.args.into_iter().map(|arg| self.fold_argument(arg)).collect();
+                Expr::Call(i)
+            }
+            _ => fold::fold_expr(self, ii)
+        }
+    }
+}
+
+impl<'a> Ownership<'a> {
+    fn fold_argument(&mut self, arg: Expr) -> Expr {
+        match &arg {
+            Expr::Reference(i) if i.mutability.is_none() && self.is_var(&i.expr) => {
+                self.is_last();
+                arg
+            }
+            _ => self.fold_expr(arg)
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn simplify(simplifier: &mut Simplifier, expr: &str) -> String {
+        let expr = simplifier.simplify(syn::parse_str(expr).unwrap());
+        quote!(#expr).to_string()
+    }
+
+    fn expected(expr: &str) -> String {
+        let expr: Expr = syn::parse_str(expr).unwrap();
+        quote!(#expr).to_string()
+    }
+
+    #[test]
+    fn folds_constants() {
+        let mut simplifier = Simplifier::new();
+        assert_eq!(simplify(&mut simplifier, "grad * (2 - 1)"), expected("grad"));
+        assert_eq!(simplify(&mut simplifier, "grad * (3 * 2)"), expected("grad * 6"));
+        assert_eq!(simplify(&mut simplifier, "grad * (1. / 4.)"), expected("grad * 0.25"));
+        assert_eq!(simplify(&mut simplifier, "grad * (2f32 * 3.)"), expected("grad * 6.0f32"));
+        // Integer division is only folded when it is exact
+        assert_eq!(simplify(&mut simplifier, "grad * (3 / 2)"), expected("grad * (3 / 2)"));
+        assert_eq!(simplify(&mut simplifier, "grad * 2.0.powi(3)"), expected("grad * 8.0"));
+        assert_eq!(simplify(&mut simplifier, "(2 as f32)"), expected("2.0f32"));
+        assert!(Simplifier::constant(syn::parse_str("-(2.5 - 0.5)").unwrap()).is_some());
+        assert!(Simplifier::constant(syn::parse_str("a - 0.5").unwrap()).is_none());
+    }
+
+    #[test]
+    fn removes_identities() {
+        let mut simplifier = Simplifier::new();
+        assert_eq!(simplify(&mut simplifier, "grad + 0."), expected("grad"));
+        assert_eq!(simplify(&mut simplifier, "0 - grad"), expected("- grad"));
+        assert_eq!(simplify(&mut simplifier, "1. * grad / 1."), expected("grad"));
+        assert_eq!(simplify(&mut simplifier, "-(-grad)"), expected("grad"));
+        assert_eq!(simplify(&mut simplifier, "a.powi(1)"), expected("a"));
+        // An operator gives an owned value, so a borrowed operand that is left is cloned
+        assert_eq!(simplify(&mut simplifier, "&a * 1."), expected("a.clone()"));
+    }
+
+    #[test]
+    fn substitutes() {
+        let mut simplifier = Simplifier::new();
+        simplifier.substitute("b", syn::parse_str("2").unwrap());
+        simplifier.substitute_shape("a", syn::parse_str("shape_0").unwrap());
+        assert_eq!(simplify(&mut simplifier, "grad * a.powi(b - 1) * b"), expected("grad * a * 2"));
+        assert_eq!(simplify(&mut simplifier, "grad.reshape(a.shape())"), expected("grad.reshape(shape_0)"));
+    }
+
+    #[test]
+    fn assigns_ownership() {
+        let mut simplifier = Simplifier::new();
+        simplifier.add_owned("a");
+        let mut exprs: Vec<Expr> = vec![
+            syn::parse_str("grad * a.cos()").unwrap(),
+            syn::parse_str("a").unwrap(),
+            syn::parse_str("f(&a, &grad) * a").unwrap(),
+        ];
+        simplifier.assign_ownership(&mut exprs);
+        let exprs: Vec<String> = exprs.iter().map(|expr| quote!(#expr).to_string()).collect();
+        assert_eq!(exprs, vec![expected("(&grad) * (&a).cos()"), expected("a.clone()"), expected("f(&a, &grad) * a")]);
+    }
+}
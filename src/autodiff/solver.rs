@@ -1,95 +1,834 @@
-use crate::reader::Arg;
-use crate::reader::Operation;
-use crate::autodiff::autodiff::{OUTPUT_NAMES, AutoDiff};
+use crate::reader::{Arg, Operation, Reader};
+use crate::autodiff::autodiff::{OUTPUT_NAMES, RESULT_NAME, RESULT, SHAPE_METHODS, AutoDiff};
+use crate::autodiff::simplifier::Simplifier;
 use quote::{quote, format_ident};
-use proc_macro2::TokenStream;
-use syn::Ident;
+use proc_macro2::{Delimiter, Group, TokenStream, TokenTree};
+use syn::{Ident, Expr};
 use std::collections::HashMap;
 
+// Arguments of the generated methods besides the input, these are owned values instead of fields of the layer
+static OWNED_LOCALS: &[&str] = &["output_grad"];
+
 pub struct Solver {
     autodiff: AutoDiff,
+    forward_autodiff: AutoDiff,
     curr_var: u32,
+    // Remaining uses of values the backward owns, the last use can move them
+    owned_uses: HashMap<String, usize>,
+    counting_uses: bool,
+    // Intermediates stored by the forward pass, in the order they were saved
+    checkpoints: Vec<(String, Arg)>,
+    // Operands of shape operations whose shapes are stored by the forward pass
+    shapes: Vec<Arg>,
+    accumulate: bool,
+    gradient_hooks: bool,
+    loss_scale: bool,
+    master_precision: Option<Expr>,
+    // Leaves with a tangent in forward mode and the tangent they start with
+    tangents: Vec<(Arg, TokenStream)>,
+    // Gradients that can be turned off at runtime, the flag telling if they are needed and how to query it
+    requires_grad: Vec<(Arg, Ident, TokenStream)>,
+    // Conditions of the branches being solved, gradients inside them are added to accumulators
+    conditions: Vec<String>,
+    accumulated: Vec<Arg>,
+    // Number of parts of every gradient, the fields that are stored already and the ones only complete at the end
+    expected: HashMap<Arg, usize>,
+    stored: Vec<Arg>,
+    partial: Vec<Arg>,
+    // Number of parts of every returned gradient that are added to its running sum already
+    summed: HashMap<Arg, usize>,
+    // Nonzero while solving branches that may be joined, their gradients are stored after the join
+    deferred: usize,
 }
 
 impl Solver {
     pub fn new() -> Solver {
         Solver {
             autodiff: AutoDiff::new(), // We should have a static instance of this
+            forward_autodiff: AutoDiff::new_forward(),
             curr_var: 1,
+            owned_uses: HashMap::new(),
+            counting_uses: false,
+            checkpoints: Vec::new(),
+            shapes: Vec::new(),
+            accumulate: false,
+            gradient_hooks: false,
+            loss_scale: false,
+            master_precision: None,
+            tangents: Vec::new(),
+            requires_grad: Vec::new(),
+            conditions: Vec::new(),
+            accumulated: Vec::new(),
+            expected: HashMap::new(),
+            stored: Vec::new(),
+            partial: Vec::new(),
+            summed: HashMap::new(),
+            deferred: 0,
         }
     }
 
-    pub fn solve(&mut self, arg_graph: Arg, grad: TokenStream, solve_for: Vec<String>) -> TokenStream {
+    /// Add a derivative rule, or replace a built in one, in the same format as `AutoDiff::add_diff`.
+    pub fn add_diff(&mut self, method: String, expressions: Vec<TokenStream>) {
+        self.autodiff.add_diff(method, expressions);
+    }
 
-        let mut solution_map: HashMap<String, Vec<TokenStream>> = HashMap::new();
-        for needed_grad in solve_for {
-            solution_map.insert(needed_grad, Vec::new());
+    /// Add a forward-mode rule, written with `tangent` instead of `grad`.
+    pub fn add_forward_diff(&mut self, method: String, expressions: Vec<TokenStream>) {
+        self.forward_autodiff.add_diff(method, expressions);
+    }
+
+    /// At a bound of a clamp the gradient goes to the bound instead of the clamped value.
+    pub fn strict_clamp(&mut self) {
+        self.autodiff.strict_clamp();
+        self.forward_autodiff.strict_clamp_forward();
+    }
+
+    /// Use intermediates saved by the forward pass instead of recomputing them from the input.
+    pub fn set_checkpoints(&mut self, checkpoints: Vec<(String, Arg)>) {
+        self.checkpoints = checkpoints;
+    }
+
+    /// Use the shapes saved by the forward pass for shape operations instead of recomputing their operands.
+    pub fn set_shapes(&mut self, shapes: Vec<Arg>) {
+        self.shapes = shapes;
+    }
+
+    /// Add the gradients to the ones already stored in the fields instead of replacing them.
+    pub fn accumulate_gradients(&mut self) {
+        self.accumulate = true;
+    }
+
+    /// Let the layer modify the gradient of every field with `self.gradient_hook(name, &mut gradient)` before it is stored.
+    pub fn gradient_hooks(&mut self) {
+        self.gradient_hooks = true;
+    }
+
+    /// Unscale the gradients of the fields by `self.loss_scale()`, a gradient that is not finite is not stored and
+    /// reported with `self.found_overflow(name)` instead.
+    pub fn loss_scale(&mut self) {
+        self.loss_scale = true;
+    }
+
+    /// Convert the gradients of the fields with `to_precision` before they are stored.
+    pub fn master_precision(&mut self, precision: Expr) {
+        self.master_precision = Some(precision);
+    }
+
+    /// Ask the layer if the fields need a gradient, branches that only lead to frozen fields are skipped.
+    pub fn set_freezable(&mut self, fields: Vec<Arg>) {
+        for field in fields {
+            let name = field.name().to_string();
+            let flag = format_ident!("requires_grad_{}", name);
+            self.requires_grad.push((field, flag, quote!(self.requires_grad(#name))));
         }
+    }
+
+    /// Ask the layer with `self.requires_input_grad()` if the input gradient is needed, it is returned as an `Option`.
+    pub fn optional_input_grad(&mut self) {
+        self.requires_grad.push((Arg::Input, format_ident!("requires_grad_input"), quote!(self.requires_input_grad())));
+    }
+
+    /// Generates the gradient calculation, gradients of fields are stored and the others are returned in order.
+    pub fn solve(&mut self, arg_graph: Arg, grad: TokenStream, solve_for: Vec<Arg>) -> TokenStream {
+
+        let mut solution_map: HashMap<Arg, Vec<TokenStream>> = HashMap::new();
+        for needed_grad in &solve_for {
+            solution_map.insert(needed_grad.clone(), Vec::new());
+        }
+
+        // Dry run to count how often the owned values are used before generating the real calculations
+        self.counting_uses = true;
+        let mut counted = solution_map.clone();
+        self.solve_operation(arg_graph.clone(), grad.clone(), &mut counted);
+        self.counting_uses = false;
+        self.curr_var = 1;
+        // The dry run also tells how many parts every gradient has, so fields can be stored after their last part
+        self.expected = counted.iter().map(|(variable, solution)| (variable.clone(), solution.len())).collect();
+        self.partial = self.accumulated.split_off(0);
+
+        let mut flags: Vec<TokenStream> = self.requires_grad.iter().map(|(_, flag, query)| {
+            quote!(let #flag = #query;)
+        }).collect();
+        if self.loss_scale {
+            flags.push(quote!(let loss_scale = self.loss_scale();));
+        }
+        let checkpoints = self.load_checkpoints();
+        let shapes = self.load_shapes();
         let calculations = self.solve_operation(arg_graph, grad, &mut solution_map);
+        let accumulators: Vec<Ident> = self.accumulated.iter().map(Solver::accumulator).collect();
+        let mut sums: Vec<Ident> = self.summed.keys().map(Solver::running_sum).collect();
+        sums.sort_by_key(|sum| sum.to_string());
 
-        // Create results of the gradient calculation
+        // Create results of the gradient calculation, fields that are not stored yet and the returned gradients
         let mut results = TokenStream::new();
+        let mut returned = Vec::new();
+
+        for variable in &solve_for {
+            let solution = &solution_map[variable];
+            if Solver::field(variable).is_some() {
+                if !self.stored.contains(variable) {
+                    let result = self.store(variable, solution);
+                    results = quote! {
+                        #results
+                        #result
+                    };
+                }
+                continue;
+            }
+            // Nothing depends on the variable, like the input of a backward pass that is linear in it
+            if solution.is_empty() && !self.accumulated.contains(variable) {
+                let ident = format_ident!("{}", variable.name());
+                returned.push(quote!(&#ident * 0.));
+                continue;
+            }
+            let total = self.total(variable, solution);
+            returned.push(match self.flag(variable) {
+                Some(_) if solution.is_empty() => {
+                    let accumulator = Solver::accumulator(variable);
+                    quote!(#accumulator)
+                }
+                Some(flag) => quote!(if #flag { Some(#total) } else { None }),
+                None => total
+            });
+        }
+
+        let returned = if returned.len() == 1 {
+            returned.remove(0)
+        } else {
+            quote!((#(#returned),*))
+        };
+
+        quote! {
+            #(#flags)*
+            #(let mut #accumulators: Option<Tensor> = None;)*
+            #(let mut #sums;)*
+            #checkpoints
+            #shapes
+            #calculations
+            #results
+            #returned
+        }
+    }
 
+    // Sum of the parts of a gradient, parts from skippable branches are only known at runtime
+    fn total(&self, variable: &Arg, solution: &[TokenStream]) -> TokenStream {
+        let parts: Vec<TokenStream> = match self.summed.get(variable) {
+            Some(summed) => {
+                let sum = Solver::running_sum(variable);
+                std::iter::once(quote!(#sum)).chain(solution[*summed..].iter().cloned()).collect()
+            }
+            None => solution.to_vec()
+        };
+        let sum = quote!(#(#parts)+*);
+        if !self.accumulated.contains(variable) {
+            return sum;
+        }
+        let accumulator = Solver::accumulator(variable);
+        if parts.is_empty() {
+            quote!(#accumulator.expect("Gradient was skipped"))
+        } else {
+            quote! {
+                match #accumulator {
+                    Some(accumulated) => accumulated + #sum,
+                    None => #sum
+                }
+            }
+        }
+    }
+
+    // Stores the gradient of a field, a field the output does not depend on keeps its gradient
+    fn store(&self, variable: &Arg, solution: &[TokenStream]) -> TokenStream {
+        if solution.is_empty() && !self.accumulated.contains(variable) {
+            return TokenStream::new();
+        }
+        let ident = Solver::field(variable).unwrap();
+        let only_accumulated = solution.is_empty();
+        let reduced = if only_accumulated {
+            Solver::reduce_to_shape(&ident, quote!(res))
+        } else {
+            Solver::reduce_to_shape(&ident, self.total(variable, solution))
+        };
+        let gradient = if self.accumulate {
+            quote! {
+                match #ident.gradient.take() {
+                    Some(gradient) => *gradient + res,
+                    None => res
+                }
+            }
+        } else {
+            quote!(res)
+        };
+        let hook = if self.gradient_hooks {
+            let name = variable.name();
+            quote! {
+                let mut res = res;
+                self.gradient_hook(#name, &mut res);
+            }
+        } else {
+            TokenStream::new()
+        };
+        let precision = match &self.master_precision {
+            Some(precision) => quote!(let res = res.to_precision(#precision);),
+            None => TokenStream::new()
+        };
+        let mut result = quote! {
+            #hook
+            #precision
+            #ident.gradient = Some(Box::new(#gradient));
+        };
+        result = if self.loss_scale {
+            let name = variable.name();
+            quote! {
+                let res = #reduced.unscale(loss_scale);
+                if res.is_finite() {
+                    #result
+                } else {
+                    self.found_overflow(#name);
+                }
+            }
+        } else {
+            quote! {
+                let res = #reduced;
+                #result
+            }
+        };
+        if only_accumulated {
+            let accumulator = Solver::accumulator(variable);
+            result = quote! {
+                if let Some(res) = #accumulator {
+                    #result
+                }
+            };
+        }
+        // A frozen field keeps its gradient, even if parts of it were computed for other gradients
+        match self.flag(variable) {
+            Some(flag) => quote! {
+                if #flag {
+                    #result
+                }
+            },
+            None => quote! {
+                {
+                    #result
+                }
+            }
+        }
+    }
+
+    // Stores the fields of which every part of the gradient is computed, so the parts are dropped right away
+    fn store_complete(&mut self, solution_map: &HashMap<Arg, Vec<TokenStream>>) -> TokenStream {
+        if self.counting_uses || !self.conditions.is_empty() || self.deferred > 0 {
+            return TokenStream::new();
+        }
+        let mut complete: Vec<&Arg> = solution_map.keys()
+            .filter(|variable| Solver::field(variable).is_some() && !self.stored.contains(variable))
+            .filter(|variable| !self.partial.contains(variable))
+            .filter(|variable| !solution_map[*variable].is_empty() && solution_map[*variable].len() == self.expected[*variable])
+            .collect();
+        complete.sort_by_key(|variable| variable.to_string());
+
+        let mut results = TokenStream::new();
+        for variable in complete {
+            let result = self.store(variable, &solution_map[variable]);
+            self.stored.push(variable.clone());
+            results = quote! {
+                #results
+                #result
+            };
+        }
+
+        // Returned gradients with more parts are added up as the parts are computed, instead of keeping every part
+        let mut returned: Vec<&Arg> = solution_map.keys()
+            .filter(|variable| Solver::field(variable).is_none() && self.expected[*variable] > 1)
+            .filter(|variable| solution_map[*variable].len() > self.summed.get(*variable).copied().unwrap_or(0))
+            .collect();
+        returned.sort_by_key(|variable| variable.to_string());
+        for variable in returned {
+            let sum = Solver::running_sum(variable);
+            let parts = &solution_map[variable][self.summed.get(variable).copied().unwrap_or(0)..];
+            let result = if self.summed.contains_key(variable) {
+                quote!(#sum = #sum + #(#parts)+*;)
+            } else {
+                quote!(#sum = #(#parts)+*;)
+            };
+            self.summed.insert(variable.clone(), solution_map[variable].len());
+            results = quote! {
+                #results
+                #result
+            };
+        }
+        results
+    }
+
+    fn flag(&self, variable: &Arg) -> Option<&Ident> {
+        self.requires_grad.iter()
+            .find(|(field, _, _)| field == variable)
+            .map(|(_, flag, _)| flag)
+    }
+
+    fn accumulator(variable: &Arg) -> Ident {
+        format_ident!("grad_{}", variable.name())
+    }
+
+    fn running_sum(variable: &Arg) -> Ident {
+        format_ident!("sum_{}", variable.name())
+    }
+
+    // The place a gradient of the variable is stored, None when it is returned instead
+    fn field(variable: &Arg) -> Option<TokenStream> {
+        match variable {
+            Arg::Parameter(name) => {
+                let name = format_ident!("{}", name);
+                Some(quote!(self.#name))
+            }
+            _ => None
+        }
+    }
+
+    // A branch is only needed if one of the gradients it leads to is, None when it is always needed
+    fn condition(&self, targets: &Vec<Arg>) -> Option<TokenStream> {
+        let mut flags = Vec::new();
+        for target in targets {
+            flags.push(self.flag(target)?);
+        }
+        Some(quote!(#(#flags)||*))
+    }
+
+    /// Differentiates into graphs instead of code, so the gradients themselves can be differentiated again.
+    pub fn solve_graph(&self, arg_graph: &Arg, grad: Arg, solve_for: &Vec<Arg>) -> HashMap<Arg, Arg> {
+        let mut solution_map: HashMap<Arg, Vec<Arg>> = HashMap::new();
+        for needed_grad in solve_for {
+            solution_map.insert(needed_grad.clone(), Vec::new());
+        }
+        self.diff_graph(arg_graph, grad, &mut solution_map);
+
+        let mut gradients = HashMap::new();
         for (variable, solution) in solution_map {
-            if variable != "input".to_string() {
-                let ident: TokenStream = variable.parse().unwrap();
-                results = quote! {
-                    {
-                        let mut res = #(#solution)+*;
-                        for i in 0..res.shape.len() {
-                            if #ident.shape[i] < res.shape[i] {
-                                res.sum(i);
-                            }
-                        }
-                        #ident.gradient = Some(Box::new(res));
+            let mut parts = solution.into_iter();
+            if let Some(first) = parts.next() {
+                let sum = parts.fold(first, |sum, part| {
+                    Arg::Operation(Box::new(Operation::new(sum, "add".to_string(), vec![part])))
+                });
+                gradients.insert(variable, sum);
+            }
+        }
+        gradients
+    }
+
+    fn diff_graph(&self, arg_graph: &Arg, grad: Arg, solution_map: &mut HashMap<Arg, Vec<Arg>>) {
+        match arg_graph {
+            Arg::None => panic!("None argument in graph!"),
+            Arg::Detached(_) => {}
+            Arg::Operation(op) => {
+                let needed_exprs = self.get_needed_expressions(op, solution_map.keys().collect());
+                let exprs = self.autodiff.get_expressions(op);
+
+                let mut op_args = vec![&op.receiver];
+                op_args.append(&mut op.args.iter().collect());
+
+                // The rule is read as an expression in which grad and the operands are parts of the graph
+                let mut objects = HashMap::new();
+                objects.insert("grad".to_string(), grad);
+                // Operands past the placeholders, like the tensors of a long list, are not read by rules
+                for (name, operand) in OUTPUT_NAMES.iter().zip(&op_args) {
+                    objects.insert(name.to_string(), (*operand).clone());
+                }
+                objects.insert(RESULT_NAME.to_string(), arg_graph.clone());
+                for i in needed_exprs {
+                    let (expr, _) = &exprs[i as usize];
+                    let next_grad = Reader::read_expr(objects.clone(), expr.clone());
+                    self.diff_graph(op_args[i as usize], next_grad, solution_map);
+                }
+            }
+            leaf => {
+                if let Some(vec) = solution_map.get_mut(leaf) {
+                    vec.push(grad);
+                }
+            }
+        }
+    }
+
+    // Sums a gradient over the dimensions in which the field was broadcasted
+    fn reduce_to_shape(ident: &TokenStream, gradient: TokenStream) -> TokenStream {
+        quote! {
+            {
+                let mut res = #gradient;
+                for i in 0..res.shape().len() {
+                    if #ident.shape()[i] < res.shape()[i] {
+                        res = res.sum(i, true);
                     }
-                    #results
                 }
+                res
+            }
+        }
+    }
+
+    /// Generates the forward-mode derivative, the tangent of the input is propagated along with every value.
+    pub fn solve_forward(&mut self, arg_graph: &Arg) -> TokenStream {
+        self.tangents = vec![(Arg::Input, quote!(tangent))];
+        let mut calculations = Vec::new();
+        let mut computed = Vec::new();
+        let (output, tangent) = self.forward_operation(arg_graph, &mut calculations, &mut computed);
+        let tangent = tangent.expect("Output does not depend on the input.");
+
+        // Values are passed on as references, the results are owned again
+        let output = Solver::owned_tokens(output);
+        let tangent = Solver::owned_tokens(tangent);
+        let result = quote!((#output, #tangent));
+        let calculations = Solver::prune_unused(calculations, &result);
+        quote! {
+            #(#calculations)*
+            #result
+        }
+    }
+
+    /// Generates Hessian-vector products by differentiating the gradient graphs of the fields in forward mode,
+    /// with the vector `v_<field>` as tangent of every field.
+    pub fn solve_hvp(&mut self, gradients: &HashMap<Arg, Arg>, fields: &[Arg]) -> TokenStream {
+        self.tangents = fields.iter().map(|field| {
+            let tangent = format_ident!("v_{}", field.name());
+            (field.clone(), quote!(#tangent))
+        }).collect();
+        let mut calculations = Vec::new();
+        let mut computed = Vec::new();
+
+        let products: Vec<TokenStream> = fields.iter().map(|field| {
+            let ident = Solver::field(field).expect("Only fields have a Hessian-vector product.");
+            let tangent = gradients.get(field)
+                .and_then(|gradient| self.forward_operation(gradient, &mut calculations, &mut computed).1);
+            match tangent {
+                Some(tangent) => Solver::reduce_to_shape(&ident, Solver::owned_tokens(tangent)),
+                // The gradient does not depend on any field, so this part of the product is zero
+                None => quote!(&#ident * 0.)
+            }
+        }).collect();
+
+        let products = if products.len() == 1 {
+            products[0].clone()
+        } else {
+            quote!((#(#products),*))
+        };
+        let calculations = Solver::prune_unused(calculations, &products);
+        quote! {
+            #(#calculations)*
+            #products
+        }
+    }
+
+    // Drops values that are not used by a later calculation or the result, like the values of the gradients
+    // of which only the tangent is needed
+    fn prune_unused(calculations: Vec<(Ident, TokenStream)>, result: &TokenStream) -> Vec<TokenStream> {
+        let mut used = Vec::new();
+        Solver::collect_idents(result.clone(), &mut used);
+        let mut kept = Vec::new();
+        for (ident, calculation) in calculations.into_iter().rev() {
+            if used.contains(&ident) {
+                Solver::collect_idents(calculation.clone(), &mut used);
+                kept.push(quote!(let #ident = #calculation;));
+            }
+        }
+        kept.reverse();
+        kept
+    }
+
+    fn collect_idents(tokens: TokenStream, idents: &mut Vec<Ident>) {
+        for token in tokens {
+            match token {
+                TokenTree::Ident(ident) => idents.push(ident),
+                TokenTree::Group(group) => Solver::collect_idents(group.stream(), idents),
+                _ => {}
+            }
+        }
+    }
+
+    fn owned_tokens(reference: TokenStream) -> TokenStream {
+        let mut expr: Expr = syn::parse2(reference.clone()).unwrap();
+        while let Expr::Paren(inner) = expr {
+            expr = *inner.expr;
+        }
+        match expr {
+            Expr::Reference(inner) if Solver::is_local(&inner.expr) => {
+                let local = inner.expr;
+                quote!(#local)
+            }
+            _ => quote!((#reference).clone())
+        }
+    }
+
+    fn is_local(expr: &Expr) -> bool {
+        match expr {
+            Expr::Path(path) => path.path.get_ident().is_some(),
+            _ => false
+        }
+    }
+
+    // Returns references to the value and tangent of the arg, the tangent is None when it does not depend on the input
+    fn forward_operation(&mut self, arg_graph: &Arg, calculations: &mut Vec<(Ident, TokenStream)>, computed: &mut Vec<(Arg, TokenStream, Option<TokenStream>)>) -> (TokenStream, Option<TokenStream>) {
+        let op = match arg_graph {
+            Arg::None => panic!("None argument in graph!"),
+            Arg::Detached(arg) => {
+                let (value, _) = self.forward_operation(arg, calculations, computed);
+                return (value, None);
+            }
+            Arg::Operation(op) => op,
+            leaf => {
+                let tangent = self.tangents.iter()
+                    .find(|(seeded, _)| seeded == leaf)
+                    .map(|(_, tangent)| tangent.clone());
+                return match leaf {
+                    // Arguments of the generated method are references already
+                    Arg::Input => (quote!(input), tangent),
+                    Arg::Local(name) if OWNED_LOCALS.contains(&name.as_str()) => {
+                        let ident = format_ident!("{}", name);
+                        (quote!(#ident), tangent)
+                    }
+                    Arg::Literal(_) => {
+                        let literal = leaf.to_tokenstream();
+                        (quote!(#literal), None)
+                    }
+                    // Other locals are written like in the backward pass, like a constant exponent of powi
+                    _ => (leaf.to_tokenstream(), tangent)
+                };
+            }
+        };
+
+        // The same part of the graph is only computed once
+        if let Some((_, value, tangent)) = computed.iter().find(|(arg, _, _)| arg == arg_graph) {
+            return (value.clone(), tangent.clone());
+        }
+
+        let mut op_args = vec![&op.receiver];
+        op_args.append(&mut op.args.iter().collect());
+        let operands: Vec<(TokenStream, Option<TokenStream>)> = op_args.iter()
+            .map(|arg| self.forward_operation(arg, calculations, computed))
+            .collect();
+
+        let value = format_ident!("x{}", self.curr_var);
+        self.curr_var += 1;
+
+        // A list is kept as a Vec, so it is borrowed like the lists of tangents, which are Vecs of tensors. The
+        // tangent of a list is the list of tangents, with zeros for the tensors that do not depend on the input
+        if op.method == "list" {
+            let elems = operands.iter().map(|(operand, _)| Operation::borrowed(operand.clone()));
+            calculations.push((value.clone(), quote!(vec![#(#elems),*])));
+            let tangent = if operands.iter().all(|(_, tangent)| tangent.is_none()) {
+                None
             } else {
-                results = quote! {
-                    #results
-                    #(#solution)+*
+                let tangents = operands.iter().map(|(operand, tangent)| match tangent {
+                    Some(tangent) => quote!(#tangent.clone()),
+                    None => quote!(#operand * 0.)
+                });
+                let tangent = format_ident!("x{}", self.curr_var);
+                self.curr_var += 1;
+                calculations.push((tangent.clone(), quote!(vec![#(#tangents),*])));
+                Some(quote!((&#tangent)))
+            };
+            let result = (quote!((&#value)), tangent);
+            computed.push((arg_graph.clone(), result.0.clone(), result.1.clone()));
+            return result;
+        }
+        let forward = Operation::format(
+            &op.method,
+            operands[0].0.clone(),
+            operands[1..].iter().map(|(operand, _)| operand.clone()).collect()
+        );
+        calculations.push((value.clone(), forward));
+
+        // Sum the tangents contributed by every operand that depends on the input
+        let rules = self.forward_autodiff.get_expressions(op);
+        let mut contributions: Vec<Expr> = Vec::new();
+        for i in 0..operands.len().min(rules.len()) {
+            if let (Some(tangent), false) = (&operands[i].1, AutoDiff::is_zero(&rules[i].0)) {
+                let mut simplifier = Simplifier::new();
+                for (name, (operand, _)) in OUTPUT_NAMES.iter().zip(&operands) {
+                    simplifier.substitute(name, syn::parse2(operand.clone()).unwrap());
                 }
+                simplifier.substitute(RESULT_NAME, syn::parse2(quote!((&#value))).unwrap());
+                simplifier.substitute("tangent", syn::parse2(tangent.clone()).unwrap());
+                let (expr, _) = &rules[i];
+                contributions.push(simplifier.simplify(expr.clone()));
             }
         }
 
+        let tangent = if contributions.is_empty() {
+            None
+        } else if contributions.len() == 1 && Solver::is_reference(&contributions[0]) {
+            // A tangent that is passed on unchanged does not need a new value
+            let reference = &contributions[0];
+            Some(quote!(#reference))
+        } else {
+            let tangent = format_ident!("x{}", self.curr_var);
+            self.curr_var += 1;
+            calculations.push((tangent.clone(), quote!(#((#contributions))+*)));
+            Some(quote!((&#tangent)))
+        };
+
+        let result = (quote!((&#value)), tangent);
+        computed.push((arg_graph.clone(), result.0.clone(), result.1.clone()));
+        result
+    }
+
+    fn is_reference(expr: &Expr) -> bool {
+        match expr {
+            Expr::Paren(inner) => Solver::is_reference(&inner.expr),
+            Expr::Reference(inner) => Solver::is_local(&inner.expr),
+            Expr::Path(path) => path.path.get_ident().is_some(),
+            _ => false
+        }
+    }
+
+    // Take the saved intermediates from the layer, only the ones used by the backward pass are kept
+    fn load_checkpoints(&self) -> TokenStream {
+        if self.checkpoints.is_empty() {
+            return TokenStream::new();
+        }
+        let loads: Vec<TokenStream> = self.checkpoints.iter().map(|(name, _)| {
+            let ident = format_ident!("saved_{}", name);
+            if self.owned_uses.get(&ident.to_string()).is_some_and(|uses| *uses > 0) {
+                quote!(let #ident = checkpoints.next().unwrap();)
+            } else {
+                quote!(checkpoints.next();)
+            }
+        }).collect();
         quote! {
-            #calculations
-            #results
+            let mut checkpoints = self.checkpoints.replace(Vec::new()).into_iter();
+            #(#loads)*
         }
     }
 
-    fn solve_operation(&mut self, arg_graph: Arg, grad: TokenStream, solution_map: &mut HashMap<String, Vec<TokenStream>>) -> TokenStream {
+    fn load_shapes(&self) -> TokenStream {
+        if self.shapes.is_empty() {
+            return TokenStream::new();
+        }
+        let loads: Vec<TokenStream> = (0..self.shapes.len()).map(|index| {
+            let ident = format_ident!("shape_{}", index);
+            if self.owned_uses.get(&ident.to_string()).is_some_and(|uses| *uses > 0) {
+                quote!(let #ident = shapes.next().unwrap();)
+            } else {
+                quote!(shapes.next();)
+            }
+        }).collect();
+        quote! {
+            let mut shapes = self.shapes.replace(Vec::new()).into_iter();
+            #(#loads)*
+        }
+    }
+
+    fn solve_operation(&mut self, arg_graph: Arg, grad: TokenStream, solution_map: &mut HashMap<Arg, Vec<TokenStream>>) -> TokenStream {
         match arg_graph {
             Arg::None => panic!("None argument in graph!"),
-            Arg::Item(mut item) => {
-                item = item.replace("&", "").replace(".", " . "); // This is not very nice at all...
-                if let Some(vec) = solution_map.get_mut(&item) {
-                    vec.push(grad);
+            Arg::Operation(op) => self.diff_operation(*op, grad, solution_map),
+            // Nothing behind a barrier needs a gradient
+            Arg::Detached(_) => TokenStream::new(),
+            variable => {
+                if !solution_map.contains_key(&variable) {
+                    return TokenStream::new();
+                }
+                if self.conditions.is_empty() {
+                    solution_map.get_mut(&variable).unwrap().push(grad);
+                    return TokenStream::new();
+                }
+                // The gradient only exists inside a skippable branch, so it is added up there
+                let accumulator = Solver::accumulator(&variable);
+                if !self.accumulated.contains(&variable) {
+                    self.accumulated.push(variable);
+                }
+                quote! {
+                    #accumulator = Some(match #accumulator.take() {
+                        Some(accumulated) => accumulated + #grad,
+                        None => #grad
+                    });
                 }
-                TokenStream::new()
             }
-            Arg::Operation(op) => self.diff_operation(*op, grad, solution_map)
         }
     }
 
     
-    fn diff_operation(&mut self, operation: Operation, grad: TokenStream, solution_map: &mut HashMap<String, Vec<TokenStream>>) -> TokenStream {
+    fn diff_operation(&mut self, operation: Operation, grad: TokenStream, solution_map: &mut HashMap<Arg, Vec<TokenStream>>) -> TokenStream {
 
         // Get expressions needed to solve for input grad and other needed grads
-        let needed_exprs = Solver::get_needed_expressions(&operation, solution_map);
+        let needed_exprs = self.get_needed_expressions(&operation, solution_map.keys().collect());
+
+        // Branches leading only to fields that can be frozen are skipped when none of them needs a gradient
+        let conditions: Vec<Option<TokenStream>> = needed_exprs.iter().map(|i| {
+            let operand = if *i == 0 { &operation.receiver } else { &operation.args[*i as usize - 1] };
+            let targets = self.targets(operand, solution_map.keys().collect());
+            self.condition(&targets)
+                .filter(|condition| self.conditions.last() != Some(&condition.to_string()))
+        }).collect();
 
-        // Construct expression inputs (grad + a & b & ...)
-        let inputs = self.define_inputs(&operation, &grad, &needed_exprs);
+        // Construct expression inputs (grad + a & b & ...), literal inputs are substituted by the simplifier
+        let mut simplifier = Simplifier::new();
+        let inputs = self.define_inputs(&operation, &grad, &needed_exprs, &mut simplifier);
 
         // Solve every expression at this level collecting the results of the expressions
-        let (expressions, next_level, idents) = self.define_expressions(operation, needed_exprs);
+        let (expressions, next_level, idents) = self.define_expressions(operation, needed_exprs, &conditions, &mut simplifier);
 
-        // Solve every every expression at sublevel with results of this level
-        let next_level_solved: Vec<TokenStream> = next_level.into_iter().map(|(arg, grad)| {
-                self.solve_operation(arg, grad, solution_map)
+        // Solve every every expression at sublevel with results of this level, keeping the gradients each one found
+        let joinable = cfg!(feature = "parallel") && next_level.len() == 2;
+        if joinable {
+            self.deferred += 1;
+        }
+        // Gradients of leaves are complete right away, storing them first frees them before the rest is solved
+        let mut branches: Vec<((Arg, TokenStream), Option<TokenStream>)> = next_level.into_iter().zip(conditions).collect();
+        branches.sort_by_key(|((arg, _), _)| matches!(arg, Arg::Operation(_)));
+        let next_level_solved: Vec<(TokenStream, Vec<TokenStream>)> = branches.into_iter().map(|((arg, grad), condition)| {
+                let found: HashMap<Arg, usize> = solution_map.iter().map(|(variable, solution)| (variable.clone(), solution.len())).collect();
+                let solved = match condition {
+                    Some(condition) => {
+                        self.conditions.push(condition.to_string());
+                        let solved = self.solve_operation(arg, grad.clone(), solution_map);
+                        self.conditions.pop();
+                        quote! {
+                            if let Some(#grad) = #grad {
+                                #solved
+                            }
+                        }
+                    }
+                    None => self.solve_operation(arg, grad, solution_map)
+                };
+                let stored = self.store_complete(solution_map);
+                let solved = quote!(#solved #stored);
+                let mut gradients: Vec<TokenStream> = solution_map.iter()
+                    .flat_map(|(variable, solution)| solution[found[variable]..].iter().cloned())
+                    .collect();
+                gradients.sort_by_key(|gradient| gradient.to_string());
+                (solved, gradients)
             }).collect();
-    
+
+        if cfg!(feature = "parallel") {
+            self.share_owned(&next_level_solved);
+        }
+        let mut fields = Vec::new();
+        let bound = if cfg!(feature = "parallel") && self.independent(&next_level_solved) {
+            Solver::bind_fields(next_level_solved[0].0.clone(), &mut fields)
+                .zip(Solver::bind_fields(next_level_solved[1].0.clone(), &mut fields))
+        } else {
+            None
+        };
+        let next_level_solved = if let Some((left, right)) = bound {
+            // Gradients found in a branch are returned from it, so they can be summed after the join
+            let left_gradients = &next_level_solved[0].1;
+            let right_gradients = &next_level_solved[1].1;
+            let locals = fields.iter().map(|field| format_ident!("self_{}", field));
+            quote! {
+                #(let #locals = &self.#fields;)*
+                let ((#(#left_gradients,)*), (#(#right_gradients,)*)) = ::rayon::join(
+                    || { #left (#(#left_gradients,)*) },
+                    || { #right (#(#right_gradients,)*) }
+                );
+            }
+        } else {
+            let next_level_solved = next_level_solved.into_iter().map(|(solved, _)| solved);
+            quote!(#(#next_level_solved)*)
+        };
+        let stored = if joinable {
+            self.deferred -= 1;
+            self.store_complete(solution_map)
+        } else {
+            TokenStream::new()
+        };
+
         // Create output block
         quote! {
             #(let #idents;)*
@@ -99,41 +838,181 @@ impl Solver {
                 #expressions
                 )*
             }
-            #(
-                #next_level_solved
-            )*
+            #next_level_solved
+            #stored
+        }
+    }
+
+    // Two branches can run at the same time if both do work, an owned value used by both is only borrowed
+    // and they do not add to the same accumulator
+    fn independent(&self, branches: &[(TokenStream, Vec<TokenStream>)]) -> bool {
+        if branches.len() != 2 || branches.iter().any(|(solved, _)| solved.is_empty()) {
+            return false;
+        }
+        let (mut left, mut left_moved) = (Vec::new(), Vec::new());
+        let (mut right, mut right_moved) = (Vec::new(), Vec::new());
+        Solver::collect_uses(branches[0].0.clone(), &mut left, &mut left_moved);
+        Solver::collect_uses(branches[1].0.clone(), &mut right, &mut right_moved);
+        let both = |name: &str| left.iter().any(|ident| ident == name) && right.iter().any(|ident| ident == name);
+
+        let moved_in_both = self.owned_uses.keys()
+            .any(|name| both(name) && (left_moved.iter().chain(&right_moved).any(|ident| ident == name)));
+        let accumulated_in_both = self.accumulated.iter()
+            .any(|variable| both(&Solver::accumulator(variable).to_string()));
+        !moved_in_both && !accumulated_in_both
+    }
+
+    // Fields read in a joined branch are borrowed before the join, so the closures capture the references instead
+    // of the layer, which does not have to be `Sync`. Branches calling methods of the layer are not joined.
+    fn bind_fields(tokens: TokenStream, fields: &mut Vec<Ident>) -> Option<TokenStream> {
+        let tokens: Vec<TokenTree> = tokens.into_iter().collect();
+        let mut bound = Vec::new();
+        let mut i = 0;
+        while i < tokens.len() {
+            match &tokens[i] {
+                TokenTree::Ident(ident) if ident == "self" => {
+                    let field = match (tokens.get(i + 1), tokens.get(i + 2), tokens.get(i + 3)) {
+                        (Some(TokenTree::Punct(dot)), Some(TokenTree::Ident(field)), next) if dot.as_char() == '.'
+                            && !matches!(next, Some(TokenTree::Group(group)) if group.delimiter() == Delimiter::Parenthesis)
+                            && !matches!(next, Some(TokenTree::Punct(colon)) if colon.as_char() == ':') => field,
+                        _ => return None
+                    };
+                    if !fields.contains(field) {
+                        fields.push(field.clone());
+                    }
+                    let local = format_ident!("self_{}", field);
+                    bound.push(TokenTree::Group(Group::new(Delimiter::Parenthesis, quote!(*#local))));
+                    i += 3;
+                }
+                TokenTree::Group(group) => {
+                    let mut inner = Group::new(group.delimiter(), Solver::bind_fields(group.stream(), fields)?);
+                    inner.set_span(group.span());
+                    bound.push(TokenTree::Group(inner));
+                    i += 1;
+                }
+                token => {
+                    bound.push(token.clone());
+                    i += 1;
+                }
+            }
         }
+        Some(bound.into_iter().collect())
     }
 
-    fn get_needed_expressions(operation: &Operation, solution_map: &HashMap<String, Vec<TokenStream>>) -> Vec<u8> {
+    // Owned values used by both branches are kept until after the join, so neither branch moves them.
+    // The dry run counts this use as well, which makes the branches borrow them in the generated code.
+    fn share_owned(&mut self, branches: &[(TokenStream, Vec<TokenStream>)]) {
+        if branches.len() != 2 {
+            return;
+        }
+        let mut left = Vec::new();
+        let mut right = Vec::new();
+        Solver::collect_idents(branches[0].0.clone(), &mut left);
+        Solver::collect_idents(branches[1].0.clone(), &mut right);
+        let shared: Vec<Ident> = self.owned_uses.keys()
+            .map(|name| format_ident!("{}", name))
+            .filter(|ident| left.contains(ident) && right.contains(ident))
+            .collect();
+        for ident in shared {
+            self.use_owned(&ident);
+        }
+    }
+
+    // Collects the identifiers in the code and the ones that are not borrowed
+    fn collect_uses(tokens: TokenStream, idents: &mut Vec<Ident>, moved: &mut Vec<Ident>) {
+        let mut borrowed = false;
+        for token in tokens {
+            match &token {
+                TokenTree::Ident(ident) => {
+                    idents.push(ident.clone());
+                    if !borrowed {
+                        moved.push(ident.clone());
+                    }
+                }
+                TokenTree::Group(group) => Solver::collect_uses(group.stream(), idents, moved),
+                _ => {}
+            }
+            borrowed = match &token {
+                TokenTree::Punct(punct) => punct.as_char() == '&',
+                _ => false
+            };
+        }
+    }
+
+    fn get_needed_expressions(&self, operation: &Operation, solve_for: Vec<&Arg>) -> Vec<u8> {
         let mut calc_expression: Vec<u8> = Vec::new();
 
         let mut op_args = vec![&operation.receiver];
         op_args.append(&mut operation.args.iter().map(|f| f).collect());
 
-        for i in 0..op_args.len() {
-            let input_n = op_args[i].to_tokenstream();
-            for to_grad_element in solution_map.keys()  {
-                if input_n.to_string().contains(to_grad_element) {
-                    calc_expression.push(i as u8);
-                }
+        // Operands without an expression, like the exponent of powi, are not differentiable
+        let exprs = self.autodiff.get_expressions(operation);
+
+        for i in 0..op_args.len().min(exprs.len()) {
+            if !AutoDiff::is_zero(&exprs[i].0) && !self.targets(op_args[i], solve_for.clone()).is_empty() {
+                calc_expression.push(i as u8);
             }
         }
         calc_expression
     }
 
-    fn define_inputs(&self, operation: &Operation, grad: &TokenStream, needed_exprs: &Vec<u8>) -> TokenStream {
-        let mut inputs: Vec<TokenStream> = Vec::new();
+    // The gradients an operand contributes to
+    fn targets(&self, operand: &Arg, solve_for: Vec<&Arg>) -> Vec<Arg> {
+        let mut targets: Vec<Arg> = solve_for.into_iter()
+            .filter(|to_grad_element| operand.depends_on(to_grad_element))
+            .cloned()
+            .collect();
+        // The map of gradients has no order, but the generated conditions should not change between builds
+        targets.sort_by_key(|target| target.to_string());
+        targets
+    }
+
+    fn define_inputs(&mut self, operation: &Operation, grad: &TokenStream, needed_exprs: &Vec<u8>, simplifier: &mut Simplifier) -> TokenStream {
+        let mut inputs: Vec<Expr> = Vec::new();
         let mut input_names = Vec::new();
 
-        let exprs = self.autodiff.get_expressions(&operation.method);
+        let exprs = self.autodiff.get_expressions(operation);
 
         let mut op_args = vec![&operation.receiver];
         op_args.append(&mut operation.args.iter().map(|f| f).collect());
 
+        // The output is loaded when it was saved and recomputed otherwise. It comes first, so the recomputation
+        // borrows owned operands before the operands can be moved
+        if needed_exprs.iter().any(|i| exprs[*i as usize].1.contains(&RESULT)) {
+            let result = Arg::Operation(Box::new(operation.clone()));
+            let input = match self.owned_value(&result) {
+                Some(ident) => {
+                    if self.use_owned(&ident) {
+                        simplifier.add_owned(RESULT_NAME);
+                        quote!(#ident)
+                    } else {
+                        quote!(&#ident)
+                    }
+                }
+                None => {
+                    simplifier.add_owned(RESULT_NAME);
+                    self.forward_tokens(&result)
+                }
+            };
+            inputs.push(Simplifier::new().simplify(syn::parse2(input).unwrap()));
+            input_names.push(format_ident!("{}", RESULT_NAME));
+        }
+
+        // A stored shape replaces the operand of a shape operation
+        let stored_shape = self.shapes.iter()
+            .position(|operand| operand == &operation.receiver)
+            .filter(|_| SHAPE_METHODS.contains(&operation.method.as_str()) && needed_exprs.contains(&0));
+        if let Some(index) = stored_shape {
+            let ident = format_ident!("shape_{}", index);
+            let shape = if self.use_owned(&ident) { quote!(#ident) } else { quote!(#ident.clone()) };
+            simplifier.substitute_shape(OUTPUT_NAMES[0], syn::parse2(shape).unwrap());
+        }
+
         // We should save and use the forward pass if needed
         for i in 0..op_args.len() {
-            let input_n = op_args[i].to_tokenstream();
+            if i == 0 && stored_shape.is_some() {
+                continue;
+            }
             let mut calc = false;
             for needed_exp in needed_exprs {
                 let (_, needed_args) = &exprs[*needed_exp as usize];
@@ -143,33 +1022,91 @@ impl Solver {
                 }
             }
             if calc {
-                inputs.push(input_n);
+                // Literals that are not numbers, like the keepdim flag of a reduction, are used as they are
+                let constant = match op_args[i] {
+                    Arg::Literal(_) => syn::parse2::<Expr>(op_args[i].to_tokenstream()).ok(),
+                    _ => syn::parse2::<Expr>(op_args[i].to_tokenstream()).ok().and_then(Simplifier::constant)
+                };
+                if let Some(constant) = constant {
+                    simplifier.substitute(OUTPUT_NAMES[i], constant);
+                    continue;
+                }
+                // Fields of the layer and owned values that are still needed later are bound as references
+                let operand = op_args[i].attached();
+                let input = match (self.owned_value(operand), operand) {
+                    (Some(ident), _) => {
+                        if self.use_owned(&ident) {
+                            simplifier.add_owned(OUTPUT_NAMES[i]);
+                            quote!(#ident)
+                        } else {
+                            quote!(&#ident)
+                        }
+                    }
+                    (None, Arg::Operation(_)) => {
+                        simplifier.add_owned(OUTPUT_NAMES[i]);
+                        self.forward_tokens(operand)
+                    }
+                    (None, Arg::None) => panic!("None argument in graph!"),
+                    (None, leaf) => {
+                        let value = Solver::field(leaf).unwrap_or_else(|| leaf.to_tokenstream());
+                        quote!(&#value)
+                    }
+                };
+                inputs.push(Simplifier::new().simplify(syn::parse2(input).unwrap()));
                 input_names.push(format_ident!("{}", OUTPUT_NAMES[i]));
             }
         }
 
-        // Since we get input as reference in the forwards pass and as owned value in the backwards pass
-        // we should replace to avoid issues. However, it would be even better to keep track of usage and
-        // use an owned value where the operation can be done inplace.
-        for i in 0..inputs.len() {
-            let mut expr_str = inputs[i].to_string();
-            expr_str = expr_str.replace("input", "(&input)");
-            inputs[i] = expr_str.parse().unwrap();
-        }
-
         quote! {
             let grad = #grad;
             #(let #input_names = #inputs;)*
         }
     }
 
-    fn define_expressions(&mut self, mut operation: Operation, needed_exprs: Vec<u8>) -> (Vec<TokenStream>, Vec<(Arg, TokenStream)>, Vec<Ident>) {
+    // We get input as reference in the forwards pass and as owned value in the backwards pass,
+    // so it is borrowed everywhere except for its last use where it can be moved into the operation.
+    // Saved intermediates are handled the same way, only the parts in between them are recomputed.
+    fn forward_tokens(&mut self, arg: &Arg) -> TokenStream {
+        arg.to_tokenstream_with(&mut |part| {
+            let ident = self.owned_value(part)?;
+            if self.use_owned(&ident) {
+                Some(quote!(#ident))
+            } else {
+                Some(quote!((&#ident)))
+            }
+        })
+    }
 
-        let mut output = Vec::new();
+    // The backward pass owns its arguments and the saved intermediates
+    fn owned_value(&self, arg: &Arg) -> Option<Ident> {
+        match arg {
+            Arg::Input => return Some(format_ident!("input")),
+            Arg::Local(name) if OWNED_LOCALS.contains(&name.as_str()) => return Some(format_ident!("{}", name)),
+            _ => {}
+        }
+        self.checkpoints.iter()
+            .find(|(_, saved)| saved == arg)
+            .map(|(name, _)| format_ident!("saved_{}", name))
+    }
+
+    // Registers a use of an owned value, returns true if it is the last use
+    fn use_owned(&mut self, ident: &Ident) -> bool {
+        let remaining = self.owned_uses.entry(ident.to_string()).or_insert(0);
+        if self.counting_uses {
+            *remaining += 1;
+            return false;
+        }
+        *remaining -= 1;
+        *remaining == 0
+    }
+
+    fn define_expressions(&mut self, operation: Operation, needed_exprs: Vec<u8>, conditions: &Vec<Option<TokenStream>>, simplifier: &mut Simplifier) -> (Vec<TokenStream>, Vec<(Arg, TokenStream)>, Vec<Ident>) {
+
+        let mut simplified: Vec<Expr> = Vec::new();
 
         let mut next_level: Vec<(Arg, TokenStream)> = Vec::new();
         let mut idents = Vec::new();
-        let exprs = self.autodiff.get_expressions(&operation.method).clone();
+        let exprs = self.autodiff.get_expressions(&operation);
         for i in 0..exprs.len() {
             if !needed_exprs.contains(&(i as u8)) {
                 continue;
@@ -183,13 +1120,24 @@ impl Solver {
             if i == 0 {
                 next_level.push((operation.receiver.clone(), ident.to_string().parse().unwrap()));
             } else {
-                next_level.push((operation.args.remove(0), ident.to_string().parse().unwrap()));
+                next_level.push((operation.args[i - 1].clone(), ident.to_string().parse().unwrap()));
             }
 
-            output.push(quote! {
-                #ident = #expr;
-            });
+            simplified.push(simplifier.simplify(expr.clone()));
         }
+        // The gradient and owned inputs belong to this level, only the last use takes them by value
+        simplifier.assign_ownership(&mut simplified);
+
+        let output = idents.iter().zip(simplified).zip(conditions).map(|((ident, expr), condition)| {
+            match condition {
+                Some(condition) => quote! {
+                    #ident = if #condition { Some(#expr) } else { None };
+                },
+                None => quote! {
+                    #ident = #expr;
+                }
+            }
+        }).collect();
         (output, next_level, idents)
     }
 }
\ No newline at end of file
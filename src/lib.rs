@@ -1,49 +1,244 @@
 #![feature(proc_macro_diagnostic)]
 mod autodiff;
 mod reader;
+mod attribute;
 
 extern crate proc_macro;
 
-use reader::Reader;
+use reader::{Reader, Arg};
+use attribute::{AttributeArg, DiffRule};
 use autodiff::solver::Solver;
+use autodiff::rule_file::{RuleFile, OPERATORS};
 use proc_macro::TokenStream;
-use quote::quote;
+use proc_macro2::TokenStream as TokenStream2;
+use quote::{quote, format_ident};
 use syn::*;
 use syn::fold::Fold;
+use syn::punctuated::Punctuated;
 
+/// Generates a `backward` method for the annotated `forward`, computing the gradients of the given fields.
+/// The supported operations, the methods the tensor type needs and the options are described in the README.
 #[proc_macro_attribute]
 pub fn into_backward(attr: TokenStream, item: TokenStream) -> TokenStream {
 
-    let mut needs_grad: Vec<String> = vec!["input".to_string()];
+    let mut needs_grad: Vec<Arg> = Vec::new();
+    let mut needs_input_grad = true;
+    let mut optional_input_grad = false;
+    let mut checkpoint_locals = false;
+    let mut double_backward = false;
+    let mut jvp = false;
+    let mut hvp = false;
+    let mut freezable = false;
+    let mut gradient_hooks = false;
+    let mut loss_scale = false;
+    let mut strict_clamp = false;
+    let mut master_precision: Option<Expr> = None;
+    let mut diff_rules: Vec<DiffRule> = Vec::new();
+    let mut forward_diff_rules: Vec<DiffRule> = Vec::new();
+    let mut operators: Vec<(String, String)> = Vec::new();
 
-    let attribute_args = parse_macro_input!(attr as AttributeArgs);
+    let attribute_args = parse_macro_input!(attr with Punctuated::<AttributeArg, Token![,]>::parse_terminated);
     for attribute in attribute_args {
-        if let NestedMeta::Meta(meta) = attribute {
-            if let Meta::Path(path) = meta {
-                let ident = path.segments.last().unwrap().ident.to_string();
-                needs_grad.push("self . ".to_string() + &ident);
-            } else {
-                panic!("Unsupported attribute argument, expected field name!")
+        match attribute {
+            AttributeArg::Path(ident) => {
+                let ident = ident.to_string();
+                match ident.as_str() {
+                    "checkpoint" => checkpoint_locals = true,
+                    "double_backward" => double_backward = true,
+                    "jvp" => jvp = true,
+                    "hvp" => hvp = true,
+                    "freezable" => freezable = true,
+                    "gradient_hooks" => gradient_hooks = true,
+                    "loss_scale" => loss_scale = true,
+                    "strict_clamp" => strict_clamp = true,
+                    "no_input_grad" => needs_input_grad = false,
+                    "optional_input_grad" => optional_input_grad = true,
+                    _ => needs_grad.push(Arg::Parameter(ident))
+                }
+            }
+            AttributeArg::NameValue(ident, lit) => {
+                if OPERATORS.contains(&ident.to_string().as_str()) {
+                    match lit {
+                        Lit::Str(method) => operators.push((ident.to_string(), method.value())),
+                        _ => panic!("The operation of an operator should be given as a string.")
+                    }
+                    continue;
+                }
+                if ident != "master_precision" {
+                    panic!("Unsupported attribute argument, expected field name!")
+                }
+                match lit {
+                    Lit::Str(precision) => master_precision = Some(precision.parse().expect("Invalid master precision.")),
+                    _ => panic!("The master precision should be given as a string.")
+                }
+            }
+            AttributeArg::Diff(rules) => {
+                diff_rules.extend(rules);
+            }
+            AttributeArg::ForwardDiff(rules) => {
+                forward_diff_rules.extend(rules);
             }
-        } else {
-            panic!("Unsupported attribute argument, expected field name!")
         }
     }
+    if needs_input_grad {
+        needs_grad.insert(0, Arg::Input);
+    }
     let mut item_fn = parse::<ItemFn>(item).unwrap();
 
+    // Rules of the project come first, so the rules and operators of a layer can replace them
+    let rule_file = RuleFile::load();
+    let mut rules: Vec<(String, Vec<TokenStream2>)> = Vec::new();
+    let mut forward_rules: Vec<(String, Vec<TokenStream2>)> = Vec::new();
+    let mut rule_file_dependency = TokenStream2::new();
+    if let Some(rule_file) = rule_file {
+        // Makes cargo build the layer again when the rules change. A file that is added later is not seen until the
+        // layer is built again for another reason
+        let path = rule_file.path.display().to_string();
+        rule_file_dependency = quote!(const _: &[u8] = include_bytes!(#path););
+        rules.extend(rule_file.rules);
+        forward_rules.extend(rule_file.forward_rules);
+        operators.splice(0..0, rule_file.operators);
+    }
+    rules.extend(diff_rules.into_iter().map(|rule| (rule.method.to_string(), rule.expressions)));
+    forward_rules.extend(forward_diff_rules.into_iter().map(|rule| (rule.method.to_string(), rule.expressions)));
+
     let mut reader = Reader::new();
+    if checkpoint_locals {
+        reader.checkpoint_locals();
+    }
+    for (operator, method) in operators {
+        reader.map_operator(operator, method);
+    }
     item_fn = reader.fold_item_fn(item_fn);
+    let checkpoints = reader.get_checkpoints();
+    let shapes = reader.get_shapes();
     let arg = reader.get_output_arg();
 
+    // Stored intermediates of a previous forward pass are not needed anymore
+    if !checkpoints.is_empty() {
+        item_fn.block.stmts.insert(0, parse_quote!(self.checkpoints.borrow_mut().clear();));
+    }
+    if !shapes.is_empty() {
+        item_fn.block.stmts.insert(0, parse_quote!(self.shapes.borrow_mut().clear();));
+    }
+
     let mut solver = Solver::new();
+    solver.set_checkpoints(checkpoints);
+    solver.set_shapes(shapes);
+    if strict_clamp {
+        solver.strict_clamp();
+    }
+    for (method, expressions) in &rules {
+        solver.add_diff(method.clone(), expressions.clone());
+    }
+
+    // The backward is expressed as a graph again, so it can be differentiated through the same rules
+    let mut double_backward_fn = TokenStream2::new();
+    if double_backward {
+        if !needs_input_grad {
+            panic!("A double backward differentiates the input gradient, it can not be used with no_input_grad.");
+        }
+        let input_grad = solver.solve_graph(&arg, Arg::Local("output_grad".to_string()), &needs_grad)
+            .remove(&Arg::Input)
+            .expect("Output does not depend on the input.");
+        let mut needs_grad_grad = needs_grad.clone();
+        needs_grad_grad.push(Arg::Local("output_grad".to_string()));
+
+        let mut double_solver = Solver::new();
+        double_solver.accumulate_gradients();
+        if strict_clamp {
+            double_solver.strict_clamp();
+        }
+        for (method, expressions) in &rules {
+            double_solver.add_diff(method.clone(), expressions.clone());
+        }
+        let double_backwards_block = double_solver.solve(input_grad, "input_grad_grad".parse().unwrap(), needs_grad_grad);
+        double_backward_fn = quote! {
+            fn backward_backward(&mut self, input: Tensor, output_grad: Tensor, input_grad_grad: Tensor) -> (Tensor, Tensor) {
+                #double_backwards_block
+            }
+        };
+    }
+
+    let mut jvp_fn = TokenStream2::new();
+    if jvp {
+        let mut jvp_solver = Solver::new();
+        if strict_clamp {
+            jvp_solver.strict_clamp();
+        }
+        for (method, expressions) in &forward_rules {
+            jvp_solver.add_forward_diff(method.clone(), expressions.clone());
+        }
+        let jvp_block = jvp_solver.solve_forward(&arg);
+        jvp_fn = quote! {
+            fn jvp(&self, input: &Tensor, tangent: &Tensor) -> (Tensor, Tensor) {
+                #jvp_block
+            }
+        };
+    }
+
+    let mut hvp_fn = TokenStream2::new();
+    if hvp {
+        let gradients = solver.solve_graph(&arg, Arg::Local("output_grad".to_string()), &needs_grad);
+        let fields: Vec<Arg> = needs_grad.iter().filter(|grad| matches!(grad, Arg::Parameter(_))).cloned().collect();
+        if fields.is_empty() {
+            panic!("A Hessian-vector product needs fields to differentiate.");
+        }
+        let tangents: Vec<Ident> = fields.iter().map(|field| format_ident!("v_{}", field.name())).collect();
+        let products: Vec<TokenStream2> = fields.iter().map(|_| quote!(Tensor)).collect();
+        let products = if products.len() == 1 { quote!(Tensor) } else { quote!((#(#products),*)) };
+
+        let mut hvp_solver = Solver::new();
+        if strict_clamp {
+            hvp_solver.strict_clamp();
+        }
+        // The gradients use the reverse rules, so their methods need forward rules as well
+        for (method, expressions) in &forward_rules {
+            hvp_solver.add_forward_diff(method.clone(), expressions.clone());
+        }
+        let hvp_block = hvp_solver.solve_hvp(&gradients, &fields);
+        hvp_fn = quote! {
+            fn hvp(&self, input: &Tensor, output_grad: &Tensor, #(#tangents: &Tensor),*) -> #products {
+                #hvp_block
+            }
+        };
+    }
+
+    if freezable {
+        solver.set_freezable(needs_grad.iter().filter(|grad| matches!(grad, Arg::Parameter(_))).cloned().collect());
+    }
+    if gradient_hooks {
+        solver.gradient_hooks();
+    }
+    if loss_scale {
+        solver.loss_scale();
+    }
+    if let Some(precision) = master_precision {
+        solver.master_precision(precision);
+    }
+    let returned = if !needs_input_grad {
+        quote!(())
+    } else if optional_input_grad {
+        solver.optional_input_grad();
+        quote!(Option<Tensor>)
+    } else {
+        quote!(Tensor)
+    };
     let backwards_block = solver.solve(arg, "output_grad".parse().unwrap(), needs_grad);
 
     let expanded = quote! {
         #item_fn
 
-        fn backward(&mut self, input: Tensor, output_grad: Tensor) -> Tensor {
+        fn backward(&mut self, input: Tensor, output_grad: Tensor) -> #returned {
+            #rule_file_dependency
             #backwards_block
         }
+
+        #double_backward_fn
+
+        #jvp_fn
+
+        #hvp_fn
     };
 
     TokenStream::from(expanded)
@@ -1,19 +1,26 @@
 use syn::fold::Fold;
 use syn::*;
-use quote::quote;
+use quote::{quote, format_ident};
 use std::mem;
 use std::default::Default;
 use std::fmt::Display;
 use std::collections::HashMap;
 use syn::spanned::Spanned;
-use proc_macro2::TokenStream;
+use proc_macro2::{TokenStream, TokenTree, Delimiter};
+use crate::autodiff::simplifier::Simplifier;
+use crate::autodiff::autodiff::SHAPE_METHODS;
 
 pub struct Reader {
     input_name: String,
     objects: HashMap<String, Arg>,
     ops: Vec<Operation>,
     current_arg: Arg,
-    output: Arg
+    output: Arg,
+    checkpoint_locals: bool,
+    checkpoints: Vec<(String, Arg)>,
+    // Operands of shape operations, in the order their shapes are stored
+    shapes: Vec<Arg>,
+    operators: HashMap<String, String>
 }
 
 impl Reader {
@@ -24,9 +31,39 @@ impl Reader {
             ops: Vec::new(),
             current_arg: Arg::None,
             output: Arg::None,
+            checkpoint_locals: false,
+            checkpoints: Vec::new(),
+            shapes: Vec::new(),
+            operators: HashMap::new(),
         }
     }
 
+    /// Read an operator as another operation, like `*` as `matmul` for a tensor type with a matrix product operator.
+    pub fn map_operator(&mut self, operator: String, method: String) {
+        self.operators.insert(operator, method);
+    }
+
+    /// Store every local variable in the forward pass instead of only the ones marked with `#[checkpoint]`.
+    pub fn checkpoint_locals(&mut self) {
+        self.checkpoint_locals = true;
+    }
+
+    /// Reads an expression in which the given variables are parts of a graph.
+    pub fn read_expr(objects: HashMap<String, Arg>, expr: Expr) -> Arg {
+        let mut reader = Reader::new();
+        reader.objects = objects;
+        reader.fold_expr(expr);
+        reader.current_arg.take()
+    }
+
+    pub fn get_checkpoints(&self) -> Vec<(String, Arg)> {
+        self.checkpoints.clone()
+    }
+
+    pub fn get_shapes(&self) -> Vec<Arg> {
+        self.shapes.clone()
+    }
+
     pub fn get_output_arg(self) -> Arg {
         self.output
     }
@@ -62,6 +99,60 @@ impl Reader {
     }
 }
 
+impl Reader {
+    // The value of a block is its last expression, the locals in it are read like any other. The block is
+    // returned with its locals rewritten, so that checkpoints in it are stored as well
+    fn read_block(&mut self, mut block: Block) -> Block {
+        self.current_arg = Arg::None;
+        for stmt in block.stmts.iter_mut() {
+            match stmt {
+                Stmt::Local(i) => {
+                    *i = self.fold_local(i.clone());
+                }
+                Stmt::Semi(i, _) => {
+                    *i = self.fold_expr(i.clone());
+                    self.current_arg.take();
+                }
+                Stmt::Expr(i) => {
+                    *i = self.fold_expr(i.clone());
+                }
+                Stmt::Item(i) => {i.span().unwrap().error("Items in blocks are not supported.").emit(); panic!("Items in blocks are not supported.")}
+            }
+        }
+        block
+    }
+}
+
+// Replaces `no_grad! { ... }` by a plain block and removes the markers of locals once the forward pass is read
+struct NoGradBlocks;
+
+impl NoGradBlocks {
+    fn parse_block(mac: &Macro) -> Block {
+        let tokens = &mac.tokens;
+        match syn::parse2(quote!({ #tokens })) {
+            Ok(block) => block,
+            Err(e) => {mac.span().unwrap().error(e.to_string()).emit(); panic!("Invalid no_grad block.")}
+        }
+    }
+}
+
+impl Fold for NoGradBlocks {
+    fn fold_expr(&mut self, ii: Expr) -> Expr {
+        if let Expr::Macro(i) = &ii {
+            if i.mac.path.is_ident("no_grad") {
+                let block = NoGradBlocks::parse_block(&i.mac);
+                return fold::fold_expr(self, Expr::Block(ExprBlock { attrs: Vec::new(), label: None, block }));
+            }
+        }
+        fold::fold_expr(self, ii)
+    }
+
+    fn fold_local(&mut self, mut ii: Local) -> Local {
+        ii.attrs.retain(|attr| !attr.path.is_ident("checkpoint") && !attr.path.is_ident("no_grad"));
+        fold::fold_local(self, ii)
+    }
+}
+
 impl Fold for Reader {
 
     fn fold_pat_type(&mut self, ii: PatType) -> PatType {
@@ -75,7 +166,7 @@ impl Fold for Reader {
     }
     
 
-    fn fold_local(&mut self, ii: Local) -> Local {
+    fn fold_local(&mut self, mut ii: Local) -> Local {
         let var_name = match &(&ii).pat {
             Pat::Ident(i) => {
                 i.ident.to_string()
@@ -85,16 +176,45 @@ impl Fold for Reader {
 
         let arg;
         if (&ii).init.is_some() {
-            let (_, exp) = ii.clone().init.unwrap();
-            self.fold_expr(*exp);
+            let (eq, exp) = ii.init.take().unwrap();
+            ii.init = Some((eq, Box::new(self.fold_expr(*exp))));
             arg = self.current_arg.take();
         } else {
             arg = Arg::None;
         }
+
+        // A #[no_grad] local is a constant to the backward pass
+        let mut arg = arg;
+        if ii.attrs.iter().any(|attr| attr.path.is_ident("no_grad")) {
+            arg = Arg::Detached(Box::new(arg));
+        }
+
+        let marked = ii.attrs.iter().any(|attr| attr.path.is_ident("checkpoint"));
+        ii.attrs.retain(|attr| !attr.path.is_ident("checkpoint") && !attr.path.is_ident("no_grad"));
+        // Only operations are worth storing, anything else is as cheap to get in the backward pass
+        if let Arg::Operation(_) = arg {
+            if marked || self.checkpoint_locals {
+                let ident = format_ident!("{}", var_name);
+                let (eq, init) = ii.init.take().unwrap();
+                ii.init = Some((eq, Box::new(parse_quote! {
+                    {
+                        let #ident = #init;
+                        self.checkpoints.borrow_mut().push(#ident.clone());
+                        #ident
+                    }
+                })));
+                self.checkpoints.push((var_name.clone(), arg.clone()));
+            }
+        }
         self.objects.insert(var_name, arg);
         ii
     }
 
+    fn fold_item_fn(&mut self, ii: ItemFn) -> ItemFn {
+        let ii = fold::fold_item_fn(self, ii);
+        NoGradBlocks.fold_item_fn(ii)
+    }
+
     fn fold_stmt(&mut self, mut ii: Stmt) -> Stmt {
         ii = fold::fold_stmt(self, ii);
         if let Stmt::Expr(i) = &ii {
@@ -107,12 +227,12 @@ impl Fold for Reader {
 
     fn fold_expr(&mut self, mut ii: Expr) -> Expr {
         match ii.clone() {
-            Expr::Binary(i) => {
+            Expr::Binary(mut i) => {
 
-                self.fold_expr(*i.left);
+                i.left = Box::new(self.fold_expr(*i.left));
                 let left = self.current_arg.take();
 
-                self.fold_expr(*i.right);
+                i.right = Box::new(self.fold_expr(*i.right));
                 let right = self.current_arg.take();
 
                 let method = match i.op {
@@ -130,10 +250,17 @@ impl Fold for Reader {
                     }
                     _ => {i.op.span().unwrap().error("Unsupported binary expression.").emit(); panic!("Unsupported binary expression.")}
                 };
-                self.current_arg = Arg::Operation(Box::new(Operation::new(left, method.to_string(), vec![right])));
+                // Numbers are scaled by the operator itself, only products of tensors are mapped
+                let literal = matches!(left, Arg::Literal(_)) || matches!(right, Arg::Literal(_));
+                let method = match self.operators.get(method) {
+                    Some(mapped) if !literal => mapped.clone(),
+                    _ => method.to_string()
+                };
+                self.current_arg = Arg::Operation(Box::new(Operation::new(left, method, vec![right])));
+                ii = Expr::Binary(i);
             }
-            Expr::Unary(i) => {
-                self.fold_expr(*i.expr);
+            Expr::Unary(mut i) => {
+                i.expr = Box::new(self.fold_expr(*i.expr));
                 let receiver = self.current_arg.take();
 
                 let op = match i.op {
@@ -142,74 +269,71 @@ impl Fold for Reader {
                     }
                     _ => {i.op.span().unwrap().error("Unsupported unary expression.").emit(); panic!("Unsupported unary expression.")}
                 };
+                // A negative number is a constant, like a bound of hardtanh
+                if let Arg::Literal(lit) = &receiver {
+                    let lit: Expr = syn::parse_str(lit).unwrap();
+                    if let Some(negated) = Simplifier::constant(parse_quote!(-#lit)) {
+                        self.current_arg = Arg::Literal(quote!(#negated).to_string());
+                        return ii;
+                    }
+                }
                 self.current_arg = Arg::Operation(Box::new(Operation::new(receiver, op.to_string(), vec![])));
+                ii = Expr::Unary(i);
             }
-            Expr::Paren(i) => {
-                self.fold_expr(*i.expr);
+            Expr::Paren(mut i) => {
+                i.expr = Box::new(self.fold_expr(*i.expr));
+                ii = Expr::Paren(i);
             }
             Expr::Assign(i) => {
-                self.fold_expr(*i.left.clone());
+                let obj_name = match &*i.left {
+                    Expr::Path(j) if j.path.get_ident().is_some() => j.path.get_ident().unwrap().to_string(),
+                    _ => {
+                        (*i.left).span().unwrap().error("Assigning to expression is not supported.").emit();
+                        panic!("Assigning to expression is not supported.");
+                    }
+                };
+                let right = self.fold_expr(*i.right.clone());
                 let arg = self.current_arg.take();
-                if let Arg::Item(obj_name) = arg {
-                    self.fold_expr(*i.right);
-                    let arg = self.current_arg.take();
-                    self.objects.insert(obj_name, arg);
-                } else {
-                    (*i.left).span().unwrap().error("Assigning to expression is not supported.").emit();
-                    panic!("Assigning to expression is not supported.");
-                }
+                self.objects.insert(obj_name, arg);
+                ii = Expr::Assign(ExprAssign { right: Box::new(right), ..i });
             }
             Expr::Lit(i) => {
                 let lit = match i.lit {
                     Lit::Int(li) => li.to_string(),
                     Lit::Float(li) => li.to_string(),
+                    // Flags like keepdim of a reduction
+                    Lit::Bool(li) => li.value.to_string(),
                     _ => {i.lit.span().unwrap().error("Unsupported literal.").emit(); panic!("Unsupported literal.")}
                 };
-                self.current_arg = Arg::Item(lit.clone());
+                self.current_arg = Arg::Literal(lit);
             }
             Expr::Path(i) => {
-                let mut path = i.path.segments.last().unwrap().ident.to_string();
-                if let Some(arg) = self.objects.get(&path) {
+                let path = &i.path;
+                let name = quote!(#path).to_string();
+                if let Some(arg) = self.objects.get(&name) {
                     self.current_arg = arg.clone();
+                } else if name == self.input_name {
+                    self.current_arg = Arg::Input;
                 } else {
-                    if path == self.input_name {
-                        path = "input".to_string();
-                    }
-                    self.current_arg = Arg::Item(path);
+                    self.current_arg = Arg::Local(name);
                 }
             }
-            //This has slightly diffent copies Path and Field, can we merge this?
-            Expr::Reference(i) => {
+            // Borrowing does not change the value, the backward pass borrows where it needs to
+            Expr::Reference(mut i) => {
                 match *i.expr {
-                    Expr::Path(j) => {
-                        let mut path = j.path.segments.last().unwrap().ident.to_string();
-                        if let Some(arg) = self.objects.get(&path) {
-                            self.current_arg = arg.clone();
-                        } else {
-                            if path == self.input_name {
-                                path = "input".to_string();
-                            }
-                            self.current_arg = Arg::Item("&".to_string() + &path);
-                        }
+                    Expr::Path(_) | Expr::Field(_) | Expr::Index(_) | Expr::MethodCall(_) | Expr::Paren(_) => {
+                        i.expr = Box::new(self.fold_expr(*i.expr));
                     }
-                    Expr::Field(j) => {
-                        let mut s = "&".to_string();
-                        match *j.base {
-                            Expr::Path(k) => {
-                                s += &k.path.get_ident().unwrap().to_string();
-                            }
-                            _ => {j.member.span().unwrap().error("Unsupported field indexing.").emit(); panic!("Unsupported field indexing.")}
-                        }
-                        match j.member {
-                            Member::Named(k) => {
-                                s += &(".".to_string() + &k.to_string());
-                            }
-                            _ => {j.member.span().unwrap().error("Only name fields can be accessed.").emit(); panic!("Only name fields can be accessed.")}
+                    // A slice like `&[2, 3]` is kept as it is written, a list of tensors is borrowed already
+                    Expr::Array(_) => {
+                        i.expr = Box::new(self.fold_expr(*i.expr));
+                        if let Arg::Literal(lit) = self.current_arg.take() {
+                            self.current_arg = Arg::Literal("& ".to_string() + &lit);
                         }
-                        self.current_arg = Arg::Item(s);
                     }
                     _ => {i.expr.span().unwrap().error("Unsupported reference.").emit(); panic!("Unsupported reference.")}
                 }
+                ii = Expr::Reference(i);
             }
             Expr::Return(mut i) => {
                 self.fold_expr(*i.clone().expr.unwrap());
@@ -217,31 +341,144 @@ impl Fold for Reader {
                 ii = Expr::Return(i);
             }
             Expr::Field(i) => {
-                let mut s = String::new();
-                match *i.base {
-                    Expr::Path(j) => {
-                        s += &j.path.get_ident().unwrap().to_string();
-                    }
+                let base = match &*i.base {
+                    Expr::Path(j) if j.path.get_ident().is_some() => j.path.get_ident().unwrap().to_string(),
                     _ => {i.member.span().unwrap().error("Unsupported field indexing.").emit(); panic!("Unsupported field indexing.")}
+                };
+                let member = match &i.member {
+                    Member::Named(j) => j.to_string(),
+                    _ => {i.member.span().unwrap().error("Only name fields can be accessed.").emit(); panic!("Only name fields can be accessed.")}
+                };
+                if base == "self" {
+                    self.current_arg = Arg::Parameter(member);
+                } else {
+                    self.current_arg = Arg::Local(base + "." + &member);
                 }
-                match i.member {
-                    Member::Named(j) => {
-
-                        s += &(".".to_string() + &j.to_string());
+            }
+            Expr::MethodCall(mut i) if i.method == "detach" && i.args.is_empty() => {
+                i.receiver = Box::new(self.fold_expr(*i.receiver));
+                self.current_arg = Arg::Detached(Box::new(self.current_arg.take()));
+                ii = Expr::MethodCall(i);
+            }
+            // Read as the block it stands for, which also replaces the macro in the forward pass
+            Expr::Macro(i) if i.mac.path.is_ident("no_grad") => {
+                let block = self.read_block(NoGradBlocks::parse_block(&i.mac));
+                self.current_arg = Arg::Detached(Box::new(self.current_arg.take()));
+                ii = Expr::Block(ExprBlock { attrs: i.attrs, label: None, block });
+            }
+            Expr::Block(mut i) => {
+                i.block = self.read_block(i.block);
+                ii = Expr::Block(i);
+            }
+            // Shapes and axes like `[2, 3]` or `(0, 2, 1)` are constants to the graph
+            Expr::Array(_) | Expr::Tuple(_) => {
+                let elems = match &mut ii {
+                    Expr::Array(ExprArray { elems, .. }) | Expr::Tuple(ExprTuple { elems, .. }) => elems,
+                    _ => unreachable!()
+                };
+                let mut list = Vec::new();
+                for elem in elems.iter_mut() {
+                    // The tensors of a list are borrowed by it
+                    let elem = match elem {
+                        Expr::Reference(reference) => &mut *reference.expr,
+                        _ => elem
+                    };
+                    *elem = self.fold_expr(elem.clone());
+                    list.push(self.current_arg.take());
+                }
+                if list.iter().all(|arg| matches!(arg, Arg::Literal(_))) {
+                    self.current_arg = Arg::Literal(quote!(#ii).to_string());
+                } else if let Expr::Tuple(_) = ii {
+                    ii.span().unwrap().error("Only tuples of numbers are supported.").emit();
+                    panic!("Only tuples of numbers are supported.");
+                } else {
+                    // A list of tensors, like the operands of `Tensor::cat(&[a, b], dim)`
+                    let receiver = list.remove(0);
+                    self.current_arg = Arg::Operation(Box::new(Operation::new(receiver, "list".to_string(), list)));
+                }
+            }
+            // Parts of a list, like the outputs of `split` and `chunk`
+            Expr::Index(mut i) => {
+                i.expr = Box::new(self.fold_expr(*i.expr));
+                let receiver = self.current_arg.take();
+                self.fold_expr(*i.index.clone());
+                let index = self.current_arg.take();
+                if !matches!(index, Arg::Literal(_)) {
+                    i.index.span().unwrap().error("Only numbers can be used as index.").emit();
+                    panic!("Only numbers can be used as index.");
+                }
+                self.current_arg = Arg::Operation(Box::new(Operation::new(receiver, "index".to_string(), vec![index])));
+                ii = Expr::Index(i);
+            }
+            // Functions of the tensor type, like `Tensor::cat(&[a, b], dim)`, are read with the first argument
+            // as receiver
+            Expr::Call(mut i) => {
+                let path = match &*i.func {
+                    Expr::Path(path) if !i.args.is_empty() => path.path.clone(),
+                    _ => {i.func.span().unwrap().error("Unsupported function call.").emit(); panic!("Unsupported function call.")}
+                };
+                let mut args = Vec::new();
+                for arg in i.args.iter_mut() {
+                    // The first argument is borrowed again when the call is written
+                    let arg = match arg {
+                        Expr::Reference(reference) if args.is_empty() => &mut *reference.expr,
+                        _ => arg
+                    };
+                    *arg = self.fold_expr(arg.clone());
+                    args.push(self.current_arg.take());
+                }
+                let receiver = args.remove(0);
+                self.current_arg = Arg::Operation(Box::new(Operation::new(receiver, quote!(#path).to_string(), args)));
+                ii = Expr::Call(i);
+            }
+            // Only conversions of numbers are supported, they are constants to the graph
+            Expr::Cast(i) => {
+                self.fold_expr(*i.expr.clone());
+                let cast = match self.current_arg.take() {
+                    Arg::Literal(lit) => {
+                        let lit: Expr = syn::parse_str(&lit).unwrap();
+                        let ty = &i.ty;
+                        Simplifier::constant(parse_quote!(#lit as #ty))
                     }
-                    _ => {i.member.span().unwrap().error("Only name fields can be accessed.").emit(); panic!("Only name fields can be accessed.")}
+                    _ => None
+                };
+                match cast {
+                    Some(cast) => self.current_arg = Arg::Literal(quote!(#cast).to_string()),
+                    None => {i.span().unwrap().error("Only numbers can be converted.").emit(); panic!("Only numbers can be converted.")}
                 }
-                self.current_arg = Arg::Item(s.clone());
             }
-            Expr::MethodCall(i) => {
-                self.fold_expr(*i.receiver);
+            Expr::MethodCall(mut i) => {
+                i.receiver = Box::new(self.fold_expr(*i.receiver));
                 let receiver = self.current_arg.take();
+                // The backward pass only needs the shape of an intermediate that is reshaped, so the forward pass
+                // stores it instead of the value being recomputed. It is stored before the arguments are computed,
+                // in the order it is read
+                if SHAPE_METHODS.contains(&i.method.to_string().as_str()) && matches!(receiver, Arg::Operation(_)) {
+                    let operand = &i.receiver;
+                    let mut inner = &**operand;
+                    while let Expr::Paren(paren) = inner {
+                        inner = &paren.expr;
+                    }
+                    i.receiver = Box::new(match inner {
+                        Expr::Path(_) => parse_quote!(({
+                            self.shapes.borrow_mut().push(#operand.shape());
+                            &#operand
+                        })),
+                        _ => parse_quote!(({
+                            let operand = #operand;
+                            self.shapes.borrow_mut().push(operand.shape());
+                            operand
+                        }))
+                    });
+                    self.shapes.push(receiver.clone());
+                }
                 let mut  args = Vec::new();
                 for k in 0..i.args.len() {
-                    self.fold_expr(i.args[k].clone());
+                    i.args[k] = self.fold_expr(i.args[k].clone());
                     args.push(self.current_arg.take());
                 }
                 self.current_arg = Arg::Operation(Box::new(Operation::new(receiver, i.method.to_string(), args)));
+                ii = Expr::MethodCall(i);
             }
 
             /*
@@ -267,11 +504,19 @@ impl Fold for Reader {
 
 }
 
-#[derive(Debug, PartialEq, Clone)]
+#[derive(Debug, PartialEq, Eq, Hash, Clone)]
 pub enum Arg {
     None,
     Operation(Box<Operation>),
-    Item(String)
+    // The input of the forward pass
+    Input,
+    // A field of the layer, `self.<name>`
+    Parameter(String),
+    // Any other variable or path, like the arguments of the generated methods
+    Local(String),
+    Literal(String),
+    // Cut from the graph by detach or no_grad, no gradient flows through it
+    Detached(Box<Arg>)
 }
 
 impl Arg {
@@ -280,10 +525,50 @@ impl Arg {
     }
 
     pub fn to_tokenstream(&self) -> TokenStream {
+        self.to_tokenstream_with(&mut |_| None)
+    }
+
+    /// Name of a leaf of the graph, like `weight` for `self.weight`.
+    pub fn name(&self) -> &str {
+        match self {
+            Arg::Input => "input",
+            Arg::Parameter(name) | Arg::Local(name) | Arg::Literal(name) => name,
+            _ => panic!("Only leaves of the graph have a name.")
+        }
+    }
+
+    /// Returns true if the gradient reaches the leaf from this part of the graph.
+    pub fn depends_on(&self, leaf: &Arg) -> bool {
+        match self {
+            Arg::Operation(op) => op.receiver.depends_on(leaf) || op.args.iter().any(|arg| arg.depends_on(leaf)),
+            Arg::Detached(_) => false,
+            _ => self == leaf
+        }
+    }
+
+    /// The part of the graph that carries the value, without the barriers around it.
+    pub fn attached(&self) -> &Arg {
+        match self {
+            Arg::Detached(arg) => arg.attached(),
+            _ => self
+        }
+    }
+
+    /// Converts to tokens, the caller can replace any part of the graph by returning tokens for it.
+    pub fn to_tokenstream_with(&self, replace: &mut dyn FnMut(&Arg) -> Option<TokenStream>) -> TokenStream {
+        if let Some(tokens) = replace(self) {
+            return tokens;
+        }
         match self {
             Arg::None => panic!(),
-            Arg::Operation(op) => op.to_tokenstream(),
-            Arg::Item(i) => i.parse().unwrap()
+            Arg::Operation(op) => op.to_tokenstream_with(replace),
+            Arg::Input => quote!(input),
+            Arg::Parameter(name) => {
+                let name = format_ident!("{}", name);
+                quote!((&self.#name))
+            }
+            Arg::Local(name) | Arg::Literal(name) => name.parse().unwrap(),
+            Arg::Detached(arg) => arg.to_tokenstream_with(replace)
         }
     }
 }
@@ -298,13 +583,25 @@ impl Display for Arg {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::result::Result<(), std::fmt::Error> {
         match self {
             Arg::Operation(op) => op.fmt(f),
-            Arg::Item(item) => write!(f, "{}", item),
+            Arg::Input => write!(f, "input"),
+            Arg::Parameter(name) => write!(f, "self.{}", name),
+            Arg::Local(name) | Arg::Literal(name) => write!(f, "{}", name),
+            Arg::Detached(arg) => write!(f, "detach({})", arg),
             _ => Ok(())
         }
     }
 }
 
-#[derive(Debug, PartialEq, Clone)]
+// Products that borrow their second operand
+static MATRIX_PRODUCTS: &[&str] = &["matmul", "bmm", "dot"];
+
+// Sparse gradients of lookups, which take the gradient as their last operand by value
+static SPARSE_METHODS: &[&str] = &["sparse_rows", "sparse_index"];
+
+// Methods that give a list of tensors, like the parts of a split
+static LIST_METHODS: &[&str] = &["list", "split", "chunk", "split_like", "unstack", "as_part"];
+
+#[derive(Debug, PartialEq, Eq, Hash, Clone)]
 pub struct Operation {
     pub receiver: Arg,
     pub method: String,
@@ -312,7 +609,7 @@ pub struct Operation {
 }
 
 impl Operation {
-    fn new(receiver: Arg, method: String, args: Vec<Arg>) -> Operation {
+    pub fn new(receiver: Arg, method: String, args: Vec<Arg>) -> Operation {
         Operation {
             receiver: receiver,
             method: method,
@@ -320,30 +617,77 @@ impl Operation {
         }
     }
 
-    pub fn to_tokenstream(&self) -> TokenStream {
-        if self.method == "add" {
-            let rec = self.receiver.to_tokenstream();
-            let arg = self.args[0].to_tokenstream();
-            quote! {#rec+#arg}
-        } else if self.method == "sub" {
-            let rec = self.receiver.to_tokenstream();
-            let arg = self.args[0].to_tokenstream();
-            quote! {#rec-#arg}
-        } else if self.method == "mul" {
-            let rec = self.receiver.to_tokenstream();
-            let arg = self.args[0].to_tokenstream();
-            quote! {#rec*#arg}
-        } else if self.method == "div" {
-            let rec = self.receiver.to_tokenstream();
-            let arg = self.args[0].to_tokenstream();
-            quote! {#rec/#arg}
-        } else if self.method == "neg" {
-            let rec = self.receiver.to_tokenstream();
-            quote! {-#rec}
+    pub fn to_tokenstream_with(&self, replace: &mut dyn FnMut(&Arg) -> Option<TokenStream>) -> TokenStream {
+        let rec = self.receiver.to_tokenstream_with(replace);
+        let args: Vec<TokenStream> = self.args.iter().map(|arg| {
+            let tokens = arg.to_tokenstream_with(replace);
+            // Lists are passed as slices
+            match arg {
+                Arg::Operation(op) if op.is_list() => quote!(&#tokens),
+                _ => tokens
+            }
+        }).collect();
+        Operation::format(&self.method, rec, args)
+    }
+
+    pub fn borrowed(tokens: TokenStream) -> TokenStream {
+        if Operation::is_reference(&tokens) { tokens } else { quote!(&#tokens) }
+    }
+
+    fn is_reference(tokens: &TokenStream) -> bool {
+        let single = tokens.clone().into_iter().count() == 1;
+        match tokens.clone().into_iter().next() {
+            Some(TokenTree::Punct(punct)) => punct.as_char() == '&',
+            Some(TokenTree::Group(group)) if single && group.delimiter() == Delimiter::Parenthesis => {
+                matches!(group.stream().into_iter().next(), Some(TokenTree::Punct(punct)) if punct.as_char() == '&')
+            }
+            _ => false
+        }
+    }
+
+    /// Returns true if the operation gives a list of tensors instead of a tensor.
+    pub fn is_list(&self) -> bool {
+        LIST_METHODS.contains(&self.method.as_str())
+    }
+
+    /// Writes the operation as code for operands that are already tokens.
+    pub fn format(method: &str, rec: TokenStream, args: Vec<TokenStream>) -> TokenStream {
+        if method == "add" {
+            let arg = &args[0];
+            quote! {(#rec+#arg)}
+        } else if method == "sub" {
+            let arg = &args[0];
+            quote! {(#rec-#arg)}
+        } else if method == "mul" {
+            let arg = &args[0];
+            quote! {(#rec*#arg)}
+        } else if method == "div" {
+            let arg = &args[0];
+            quote! {(#rec/#arg)}
+        } else if method == "neg" {
+            quote! {(-#rec)}
+        } else if method == "list" {
+            // Every tensor is borrowed once, so the list has one type and the borrowed values live as long as it
+            let elems = std::iter::once(rec).chain(args).map(Operation::borrowed);
+            quote! {[#(#elems),*]}
+        } else if method == "index" {
+            let arg = &args[0];
+            quote! {#rec[#arg].clone()}
+        } else if MATRIX_PRODUCTS.contains(&method) {
+            // The operand is borrowed like in `a.matmul(&b)`, an operand that is a reference already is coerced
+            let met: TokenStream = method.parse().unwrap();
+            let arg = &args[0];
+            quote! {#rec.#met(&#arg)}
+        } else if SPARSE_METHODS.contains(&method) && args.last().is_some_and(Operation::is_reference) {
+            // Forward mode borrows every value, so a borrowed gradient is cloned
+            let met: TokenStream = method.parse().unwrap();
+            let (grad, args) = args.split_last().unwrap();
+            quote! {#rec.#met(#(#args,)* #grad.clone())}
+        } else if method.contains("::") {
+            let path: TokenStream = method.parse().unwrap();
+            quote! {#path(&#rec, #(#args),*)}
         } else {
-            let rec = self.receiver.to_tokenstream();
-            let met: TokenStream = self.method.parse().unwrap();
-            let args: Vec<TokenStream> = self.args.iter().map(|arg| arg.to_tokenstream()).collect();
+            let met: TokenStream = method.parse().unwrap();
             quote! {#rec.#met(#(#args),*)}
         }
     }